@@ -0,0 +1,501 @@
+//! Async client built on top of tokio.
+//!
+//! This module is only available with the `async` feature. The connection is driven by the
+//! same dispatcher as the `dispatch` function, on a background thread, so the channels, users
+//! and topics tracked by `Irc` behave exactly the same. Events are delivered through a `Stream`
+//! and commands are written from tokio's blocking pool.
+//!
+//! The events are buffered up to `EVENT_CAPACITY`. When the buffer is full, the dispatcher
+//! waits for the `Events` stream to be read before reading more messages from the server, so
+//! nothing is lost, but a consumer which stops reading for too long stalls the connection and
+//! may get it dropped for not answering the PINGs of the server.
+//!
+//! Writes are blocking under the hood: every command is a synchronous write on the socket,
+//! made from tokio's blocking pool while holding the lock of the connection, so a slow socket
+//! can keep threads of the pool busy.
+//!
+//! ```ignore
+//! let (irc, mut events) = async_client::connect(Settings::new("irc.freenode.net:6667", "hiirc")).await?;
+//! irc.join("#hiirc", None).await?;
+//! while let Some(event) = events.recv().await {
+//!     if let Event::ChannelMsg { channel, message, .. } = event {
+//!         irc.privmsg(channel.name(), &message).await?;
+//!     }
+//! }
+//! ```
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+
+use futures_core::Stream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinHandle};
+
+use core::{dispatch, Error, Irc, IrcWrite};
+use event::{Event, Sink};
+use hostmask::BanStyle;
+use isupport::{ISupport, ModeChange};
+use list::ListFilter;
+use loirc::PrefixUser;
+use settings::Settings;
+
+/// Number of events buffered before the dispatcher waits for the `Events` stream to be read.
+pub const EVENT_CAPACITY: usize = 1024;
+
+type Ready = Arc<Mutex<Option<oneshot::Sender<Result<Arc<Irc>, Error>>>>>;
+
+fn resolve(ready: &Ready, result: Result<Arc<Irc>, Error>) {
+    if let Some(sender) = ready.lock().unwrap().take() {
+        let _ = sender.send(result);
+    }
+}
+
+/// Connect to the server and perform registration.
+///
+/// The returned future resolves once the server has sent the welcome message. The `Events`
+/// stream contains every event since the connection was opened, including the welcome.
+/// If the `Events` stream is dropped, the connection is closed.
+pub fn connect(settings: Settings<'static>) -> Connect {
+    let (events_tx, events_rx) = mpsc::channel(EVENT_CAPACITY);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let ready: Ready = Arc::new(Mutex::new(Some(ready_tx)));
+
+    let sink_ready = ready.clone();
+    let sink = Sink::new(move |irc: &Arc<Irc>, event: Event| {
+        match event {
+            Event::Welcome => resolve(&sink_ready, Ok(irc.clone())),
+            Event::Close(_) => resolve(&sink_ready, Err(Error::Closed)),
+            _ => {}
+        }
+        if events_tx.blocking_send(event).is_err() {
+            let _ = irc.close();
+        }
+    });
+
+    thread::spawn(move || {
+        if let Err(err) = dispatch(sink, settings) {
+            resolve(&ready, Err(err));
+        }
+    });
+
+    Connect {
+        ready: ready_rx,
+        events: Some(Events {
+            rx: events_rx,
+        }),
+    }
+}
+
+/// Future returned by `connect`.
+pub struct Connect {
+    ready: oneshot::Receiver<Result<Arc<Irc>, Error>>,
+    events: Option<Events>,
+}
+
+impl Future for Connect {
+    type Output = Result<(AsyncIrc, Events), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match Pin::new(&mut self.ready).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(Ok(irc))) => {
+                let events = self.events.take().expect("Connect polled after completion");
+                Poll::Ready(Ok((AsyncIrc { irc }, events)))
+            }
+            Poll::Ready(Ok(Err(err))) => Poll::Ready(Err(err)),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(Error::Closed)),
+        }
+    }
+}
+
+/// Stream of the events produced by the connection.
+///
+/// The stream ends when the connection is closed.
+pub struct Events {
+    rx: mpsc::Receiver<Event>,
+}
+
+impl Events {
+
+    /// Receive the next event.
+    ///
+    /// Returns `None` once the connection is closed and every event was received.
+    pub fn recv(&mut self) -> Recv<'_> {
+        Recv {
+            events: self,
+        }
+    }
+
+}
+
+impl Stream for Events {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Event>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Future returned by `Events::recv`.
+pub struct Recv<'a> {
+    events: &'a mut Events,
+}
+
+impl<'a> Future for Recv<'a> {
+    type Output = Option<Event>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Event>> {
+        self.events.rx.poll_recv(cx)
+    }
+}
+
+/// Future returned by the commands of `AsyncIrc`.
+pub struct Write {
+    handle: JoinHandle<Result<(), Error>>,
+}
+
+impl Future for Write {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        match Pin::new(&mut self.handle).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(res)) => Poll::Ready(res),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(Error::IoError(io::Error::other(err)))),
+        }
+    }
+}
+
+// Records the lines produced by the IrcWrite methods, so that they can be sent later on.
+struct Lines {
+    lines: RefCell<Vec<String>>,
+    isupport: ISupport,
+}
+
+impl IrcWrite for Lines {
+
+    fn raw<S: AsRef<str>>(&self, raw: S) -> Result<(), Error> {
+        self.lines.borrow_mut().push(raw.as_ref().into());
+        Ok(())
+    }
+
+    fn isupport(&self) -> ISupport {
+        self.isupport.clone()
+    }
+
+}
+
+/// Async handle to the connection.
+///
+/// Every command mirrors the method of the same name in `IrcWrite`. They must be called from
+/// within a tokio runtime, because the writes happen on its blocking pool.
+#[derive(Clone)]
+pub struct AsyncIrc {
+    irc: Arc<Irc>,
+}
+
+impl AsyncIrc {
+
+    /// Get the underlying `Irc`, to access channels and users.
+    pub fn irc(&self) -> &Arc<Irc> {
+        &self.irc
+    }
+
+    /// Close the underlying connection.
+    pub fn close(&self) -> Result<(), Error> {
+        self.irc.close()
+    }
+
+    fn send<F>(&self, f: F) -> Write
+        where F: FnOnce(&Lines) -> Result<(), Error>
+    {
+        self.send_then(f, |_| {})
+    }
+
+    // Like `send`, then call `then` once every line was written.
+    fn send_then<F, T>(&self, f: F, then: T) -> Write
+        where F: FnOnce(&Lines) -> Result<(), Error>,
+              T: FnOnce(&Irc) + Send + 'static
+    {
+        let lines = Lines {
+            lines: RefCell::new(Vec::new()),
+            isupport: self.irc.isupport(),
+        };
+        let res = f(&lines);
+        let lines = lines.lines.into_inner();
+        let irc = self.irc.clone();
+
+        Write {
+            handle: task::spawn_blocking(move || {
+                res?;
+                for line in lines {
+                    irc.raw(line)?;
+                }
+                then(&irc);
+                Ok(())
+            }),
+        }
+    }
+
+    /// See `IrcWrite::raw`.
+    pub fn raw<S: AsRef<str>>(&self, raw: S) -> Write {
+        self.send(|w| w.raw(raw))
+    }
+
+    /// See `IrcWrite::command`.
+    pub fn command(&self, command: &str, params: &[&str], trailing: Option<&str>) -> Write {
+        self.send(|w| w.command(command, params, trailing))
+    }
+
+    /// See `IrcWrite::nick`.
+    pub fn nick(&self, nickname: &str) -> Write {
+        self.send(|w| w.nick(nickname))
+    }
+
+    /// See `IrcWrite::user`.
+    pub fn user(&self, username: &str, realname: &str) -> Write {
+        self.send(|w| w.user(username, realname))
+    }
+
+    /// See `IrcWrite::ping`.
+    pub fn ping(&self, server: &str) -> Write {
+        self.send(|w| w.ping(server))
+    }
+
+    /// See `IrcWrite::pong`.
+    pub fn pong(&self, server: &str) -> Write {
+        self.send(|w| w.pong(server))
+    }
+
+    /// See `IrcWrite::pass`.
+    pub fn pass(&self, password: &str) -> Write {
+        self.send(|w| w.pass(password))
+    }
+
+    /// See `IrcWrite::privmsg`.
+    pub fn privmsg(&self, target: &str, text: &str) -> Write {
+        self.send(|w| w.privmsg(target, text))
+    }
+
+    /// See `IrcWrite::notice`.
+    pub fn notice(&self, target: &str, text: &str) -> Write {
+        self.send(|w| w.notice(target, text))
+    }
+
+    /// See `IrcWrite::join`.
+    pub fn join(&self, channel: &str, password: Option<&str>) -> Write {
+        self.send(|w| {
+            w.join(channel, password)?;
+            self.irc.joining(channel, password);
+            Ok(())
+        })
+    }
+
+    /// See `IrcWrite::part`.
+    pub fn part(&self, channel: &str, message: Option<&str>) -> Write {
+        self.send(|w| w.part(channel, message))
+    }
+
+    /// See `IrcWrite::quit`.
+    pub fn quit(&self, message: Option<&str>) -> Write {
+        self.send(|w| w.quit(message))
+    }
+
+    /// See `IrcWrite::away`.
+    pub fn away(&self, message: Option<&str>) -> Write {
+        self.send(|w| {
+            w.away(message)?;
+            self.irc.going_away(message);
+            Ok(())
+        })
+    }
+
+    /// See `Irc::watch`.
+    pub fn watch(&self, nicknames: &[&str]) -> Write {
+        self.send(|w| self.irc.watch_with(w, nicknames))
+    }
+
+    /// See `Irc::unwatch`.
+    pub fn unwatch(&self, nicknames: &[&str]) -> Write {
+        self.send(|w| self.irc.unwatch_with(w, nicknames))
+    }
+
+    /// See `IrcWrite::get_topic`.
+    pub fn get_topic(&self, channel: &str) -> Write {
+        self.send(|w| w.get_topic(channel))
+    }
+
+    /// See `IrcWrite::list`.
+    pub fn list(&self, filter: Option<&ListFilter>) -> Write {
+        let param = self.irc.list_param(filter);
+        let filter = filter.cloned();
+        self.send_then(|w| match param {
+            None => w.command("LIST", &[], None),
+            Some(ref param) => w.command("LIST", &[param], None),
+        }, move |irc| irc.start_list(filter.as_ref()))
+    }
+
+    /// See `IrcWrite::invite`.
+    pub fn invite(&self, nickname: &str, channel: &str) -> Write {
+        self.send(|w| w.invite(nickname, channel))
+    }
+
+    /// See `IrcWrite::get_invites`.
+    pub fn get_invites(&self, channel: &str) -> Write {
+        self.send(|w| w.get_invites(channel))
+    }
+
+    /// See `IrcWrite::get_bans`.
+    pub fn get_bans(&self, channel: &str) -> Write {
+        self.send(|w| w.get_bans(channel))
+    }
+
+    /// See `IrcWrite::set_topic`.
+    pub fn set_topic(&self, channel: &str, topic: &str) -> Write {
+        self.send(|w| w.set_topic(channel, topic))
+    }
+
+    /// See `IrcWrite::kick`.
+    pub fn kick(&self, channel: &str, nickname: &str, reason: Option<&str>) -> Write {
+        self.send(|w| w.kick(channel, nickname, reason))
+    }
+
+    /// See `IrcWrite::mode`.
+    pub fn mode(&self, target: &str, modes: &str, params: &[&str]) -> Write {
+        self.send(|w| w.mode(target, modes, params))
+    }
+
+    /// See `IrcWrite::change_modes`.
+    pub fn change_modes(&self, channel: &str, changes: &[ModeChange]) -> Write {
+        self.send(|w| w.change_modes(channel, changes))
+    }
+
+    /// See `IrcWrite::op`.
+    pub fn op(&self, channel: &str, nicknames: &[&str]) -> Write {
+        self.send(|w| w.op(channel, nicknames))
+    }
+
+    /// See `IrcWrite::deop`.
+    pub fn deop(&self, channel: &str, nicknames: &[&str]) -> Write {
+        self.send(|w| w.deop(channel, nicknames))
+    }
+
+    /// See `IrcWrite::halfop`.
+    pub fn halfop(&self, channel: &str, nicknames: &[&str]) -> Write {
+        self.send(|w| w.halfop(channel, nicknames))
+    }
+
+    /// See `IrcWrite::dehalfop`.
+    pub fn dehalfop(&self, channel: &str, nicknames: &[&str]) -> Write {
+        self.send(|w| w.dehalfop(channel, nicknames))
+    }
+
+    /// See `IrcWrite::voice`.
+    pub fn voice(&self, channel: &str, nicknames: &[&str]) -> Write {
+        self.send(|w| w.voice(channel, nicknames))
+    }
+
+    /// See `IrcWrite::devoice`.
+    pub fn devoice(&self, channel: &str, nicknames: &[&str]) -> Write {
+        self.send(|w| w.devoice(channel, nicknames))
+    }
+
+    /// See `IrcWrite::ban`.
+    pub fn ban(&self, channel: &str, masks: &[&str]) -> Write {
+        self.send(|w| w.ban(channel, masks))
+    }
+
+    /// See `IrcWrite::unban`.
+    pub fn unban(&self, channel: &str, masks: &[&str]) -> Write {
+        self.send(|w| w.unban(channel, masks))
+    }
+
+    /// See `IrcWrite::kickban`.
+    pub fn kickban(&self, channel: &str, user: &PrefixUser, style: BanStyle, reason: Option<&str>) -> Write {
+        self.send(|w| w.kickban(channel, user, style, reason))
+    }
+
+    /// See `IrcWrite::quiet`.
+    pub fn quiet(&self, channel: &str, masks: &[&str]) -> Write {
+        self.send(|w| w.quiet(channel, masks))
+    }
+
+    /// See `IrcWrite::unquiet`.
+    pub fn unquiet(&self, channel: &str, masks: &[&str]) -> Write {
+        self.send(|w| w.unquiet(channel, masks))
+    }
+
+    /// See `IrcWrite::set_key`.
+    pub fn set_key(&self, channel: &str, key: Option<&str>) -> Write {
+        self.send(|w| w.set_key(channel, key))
+    }
+
+    /// See `IrcWrite::set_limit`.
+    pub fn set_limit(&self, channel: &str, limit: Option<u32>) -> Write {
+        self.send(|w| w.set_limit(channel, limit))
+    }
+
+}
+
+#[cfg(test)]
+fn test_connect(server: &::testing::TestServer) -> (tokio::runtime::Runtime, AsyncIrc, Events, ::testing::TestClient) {
+    let addr: &'static str = Box::leak(server.addr().to_string().into_boxed_str());
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let connect = connect(Settings::new(addr, "me"));
+    let mut client = server.accept();
+    client.until("USER");
+    client.send(":server 001 me :Welcome");
+    let (irc, events) = runtime.block_on(connect).unwrap();
+    (runtime, irc, events, client)
+}
+
+#[test]
+fn test_bounded_events() {
+    use std::time::{Duration, Instant};
+
+    let server = ::testing::TestServer::new();
+    let (runtime, _irc, mut events, mut client) = test_connect(&server);
+    let count = EVENT_CAPACITY + 100;
+    for i in 0..count {
+        client.send(&format!(":alice!a@host PRIVMSG me :{}", i));
+    }
+
+    // The dispatcher waits for the stream once the buffer is full.
+    let start = Instant::now();
+    while events.rx.len() < EVENT_CAPACITY {
+        assert!(start.elapsed() < Duration::from_secs(5), "the buffer was never filled");
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(events.rx.len(), EVENT_CAPACITY);
+
+    // Nothing is lost and the events arrive in order.
+    let mut next = 0;
+    while next < count {
+        if let Event::PrivateMsg { message, .. } = runtime.block_on(events.recv()).unwrap() {
+            assert_eq!(message, next.to_string());
+            next += 1;
+        }
+    }
+}
+
+#[test]
+fn test_failed_write() {
+    let server = ::testing::TestServer::new();
+    let (runtime, irc, _events, _client) = test_connect(&server);
+    let _runtime = runtime.enter();
+
+    assert!(runtime.block_on(irc.join("#chan", Some("key :x"))).is_err());
+    assert!(!irc.irc().is_joining("#chan"));
+    assert!(runtime.block_on(irc.away(Some("gone\0"))).is_err());
+    assert!(irc.irc().away_message().is_none());
+    assert!(runtime.block_on(irc.list(Some(&ListFilter::default().mask("#a b")))).is_err());
+
+    assert!(runtime.block_on(irc.join("#chan", Some("key"))).is_ok());
+    assert!(irc.irc().is_joining("#chan"));
+}
@@ -0,0 +1,96 @@
+//! Capability negotiation and SASL authentication.
+//!
+//! When capabilities are requested with `Settings::cap`, or SASL is enabled with
+//! `Settings::sasl`, the client sends `CAP LS 302` before registering. It requests the
+//! capabilities the server offers among the wanted ones, authenticates with SASL `PLAIN` if
+//! the server acknowledged `sasl`, and ends the negotiation with `CAP END`. The status of the
+//! connection is `NegotiatingCaps`, then `Authenticating`, until the negotiation is over.
+//!
+//! The capabilities enabled by the server are available with `Irc::caps`.
+//!
+//! ```ignore
+//! let settings = Settings::new("irc.libera.chat:6667", "hiirc")
+//!     .cap("account-notify")
+//!     .cap("extended-join")
+//!     .sasl("hiirc", "secret");
+//! ```
+
+use std::mem;
+
+/// Capabilities offered by the server in the lines of a `CAP LS` reply.
+#[derive(Debug, Default)]
+pub(crate) struct Negotiation {
+    offered: Vec<String>,
+}
+
+impl Negotiation {
+
+    /// Handle a line of the `CAP LS` reply.
+    ///
+    /// Returns the capabilities to request once the last line is received, among the wanted
+    /// ones. Values such as `sasl=PLAIN` are not part of the names.
+    pub fn ls(&mut self, caps: &str, last: bool, wanted: &[&str]) -> Option<Vec<String>> {
+        self.offered.extend(caps.split_whitespace().map(|c| c.split('=').next().unwrap_or(c).to_string()));
+        if !last {
+            return None;
+        }
+        let offered = mem::take(&mut self.offered);
+        Some(wanted.iter()
+            .filter(|w| offered.iter().any(|o| o.eq_ignore_ascii_case(w)))
+            .map(|w| w.to_string())
+            .collect())
+    }
+
+}
+
+/// Build the `AUTHENTICATE` payloads of a SASL `PLAIN` authentication.
+///
+/// The credentials are split in chunks of 400 bytes, followed by `+` if the last chunk is full.
+pub(crate) fn plain_payloads(account: &str, password: &str) -> Vec<String> {
+    let encoded = base64(format!("{}\0{}\0{}", account, account, password).as_bytes());
+    let mut payloads: Vec<String> = encoded.as_bytes().chunks(400)
+        .map(|c| String::from_utf8_lossy(c).into_owned())
+        .collect();
+    if payloads.last().map(|p| p.len() == 400).unwrap_or(true) {
+        payloads.push("+".into());
+    }
+    payloads
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[test]
+fn test_negotiation() {
+    let mut negotiation = Negotiation::default();
+    let wanted = ["sasl", "account-notify", "away-notify"];
+    assert_eq!(negotiation.ls("multi-prefix sasl=PLAIN,EXTERNAL", false, &wanted), None);
+    assert_eq!(negotiation.ls("Account-Notify", true, &wanted), Some(vec!["sasl".into(), "account-notify".into()]));
+    assert_eq!(negotiation.ls("", true, &wanted), Some(vec![]));
+}
+
+#[test]
+fn test_plain_payloads() {
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"foob"), "Zm9vYg==");
+    assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    assert_eq!(plain_payloads("jilles", "sesame"), vec!["amlsbGVzAGppbGxlcwBzZXNhbWU="]);
+    assert_eq!(plain_payloads("", "")[0], "AAA=");
+
+    let payloads = plain_payloads("a", &"x".repeat(296));
+    assert_eq!(payloads.iter().map(|p| p.len()).collect::<Vec<_>>(), vec![400, 1]);
+}
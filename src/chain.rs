@@ -0,0 +1,268 @@
+//! Composition of many listeners and middlewares on a single connection.
+//!
+//! Events travel through the chain in the order in which the handlers were added. A
+//! `Middleware` can inspect or modify an event before it reaches the handlers placed after
+//! it, or stop its propagation altogether.
+//!
+//! ```ignore
+//! let ignore = |_: Arc<Irc>, event: &mut Event| match *event {
+//!     Event::PrivateMsg { ref sender, .. } if sender.nickname == "spammer" => Flow::Stop,
+//!     _ => Flow::Continue,
+//! };
+//!
+//! Settings::new("irc.freenode.net:6667", "hiirc")
+//!     .dispatch_chain(Chain::new().middleware(ignore).listener(Logger).listener(Bot))
+//!     .unwrap();
+//! ```
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use event::Event;
+use {Irc, Listener};
+
+/// Tells the chain what to do with an event after a `Middleware` handled it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Flow {
+    /// Give the event to the next handler in the chain.
+    Continue,
+    /// Drop the event, the handlers after this one will not see it.
+    Stop,
+}
+
+/// Implement this trait to filter or modify events before they reach the listeners.
+///
+/// It's implemented for closures with the same signature as `handle`.
+pub trait Middleware {
+
+    /// Handle an event travelling through the chain.
+    fn handle(&mut self, irc: Arc<Irc>, event: &mut Event) -> Flow;
+
+}
+
+impl<F> Middleware for F where F: FnMut(Arc<Irc>, &mut Event) -> Flow {
+
+    fn handle(&mut self, irc: Arc<Irc>, event: &mut Event) -> Flow {
+        self(irc, event)
+    }
+
+}
+
+/// Identifies a handler of a chain, so that it can be removed.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct HandlerId(usize);
+
+impl HandlerId {
+
+    pub(crate) fn next() -> HandlerId {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        HandlerId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+}
+
+enum Handler<'a> {
+    Listener(Box<dyn Listener + 'a>),
+    Middleware(Box<dyn Middleware + 'a>),
+}
+
+/// Change to a chain requested with `Irc` from inside a callback.
+pub(crate) enum ChainOp {
+    AddListener(HandlerId, Box<dyn Listener + Send>),
+    AddMiddleware(HandlerId, Box<dyn Middleware + Send>),
+    Remove(HandlerId),
+}
+
+/// An ordered list of listeners and middlewares.
+#[derive(Default)]
+pub struct Chain<'a> {
+    handlers: Vec<(HandlerId, Handler<'a>)>,
+}
+
+impl<'a> Chain<'a> {
+
+    /// Create an empty chain.
+    pub fn new() -> Chain<'a> {
+        Chain {
+            handlers: Vec::new(),
+        }
+    }
+
+    /// Add a listener at the end of the chain.
+    pub fn listener<L>(mut self, listener: L) -> Chain<'a>
+        where L: Listener + 'a
+    {
+        self.add_listener(listener);
+        self
+    }
+
+    /// Add a middleware at the end of the chain.
+    pub fn middleware<M>(mut self, middleware: M) -> Chain<'a>
+        where M: Middleware + 'a
+    {
+        self.add_middleware(middleware);
+        self
+    }
+
+    /// Add a listener at the end of the chain and get its id.
+    pub fn add_listener<L>(&mut self, listener: L) -> HandlerId
+        where L: Listener + 'a
+    {
+        let id = HandlerId::next();
+        self.handlers.push((id, Handler::Listener(Box::new(listener))));
+        id
+    }
+
+    /// Add a middleware at the end of the chain and get its id.
+    pub fn add_middleware<M>(&mut self, middleware: M) -> HandlerId
+        where M: Middleware + 'a
+    {
+        let id = HandlerId::next();
+        self.handlers.push((id, Handler::Middleware(Box::new(middleware))));
+        id
+    }
+
+    /// Remove a handler from the chain.
+    ///
+    /// Returns false if there is no handler with this id.
+    pub fn remove(&mut self, id: HandlerId) -> bool {
+        match self.handlers.iter().position(|&(hid, _)| hid == id) {
+            Some(pos) => {
+                self.handlers.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get the number of handlers in the chain.
+    pub fn len(&self) -> usize {
+        self.handlers.len()
+    }
+
+    /// Check if the chain has no handlers.
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Send an event through the chain.
+    ///
+    /// Returns `Flow::Stop` if a middleware stopped the propagation of the event.
+    pub(crate) fn emit(&mut self, irc: &Arc<Irc>, event: &mut Event) -> Flow {
+        for &mut (_, ref mut handler) in self.handlers.iter_mut() {
+            match *handler {
+                Handler::Listener(ref mut listener) => {
+                    event.deliver(irc.clone(), &mut **listener);
+                }
+                Handler::Middleware(ref mut middleware) => {
+                    if middleware.handle(irc.clone(), event) == Flow::Stop {
+                        return Flow::Stop;
+                    }
+                }
+            }
+        }
+        Flow::Continue
+    }
+
+    pub(crate) fn apply(&mut self, ops: Vec<ChainOp>) {
+        for op in ops {
+            match op {
+                ChainOp::AddListener(id, listener) => {
+                    self.handlers.push((id, Handler::Listener(listener)));
+                }
+                ChainOp::AddMiddleware(id, middleware) => {
+                    self.handlers.push((id, Handler::Middleware(middleware)));
+                }
+                ChainOp::Remove(id) => {
+                    self.remove(id);
+                }
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use loirc::PrefixUser;
+#[cfg(test)]
+use testing::{message, TestServer};
+
+// Logs the private messages it receives, prefixed with its name.
+#[cfg(test)]
+struct Logger(&'static str, Arc<Mutex<Vec<String>>>);
+
+#[cfg(test)]
+impl Listener for Logger {
+    fn private_msg(&mut self, _: Arc<Irc>, _: &PrefixUser, message: &str) {
+        self.1.lock().unwrap().push(format!("{}:{}", self.0, message));
+    }
+}
+
+#[cfg(test)]
+fn test_feed(chain: Chain, messages: &[&str]) {
+    let server = TestServer::new();
+    let (mut dispatch, _client) = server.connect(chain, server.settings());
+    for text in messages {
+        dispatch.feed(&message(&format!(":alice!a@host PRIVMSG me :{}", text)));
+    }
+}
+
+#[test]
+fn test_listener_order() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let chain = Chain::new().listener(Logger("a", log.clone())).listener(Logger("b", log.clone()));
+    test_feed(chain, &["hi", "bye"]);
+    assert_eq!(*log.lock().unwrap(), ["a:hi", "b:hi", "a:bye", "b:bye"]);
+}
+
+#[test]
+fn test_middleware_stop() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let stop = |_: Arc<Irc>, event: &mut Event| match *event {
+        Event::PrivateMsg { ref message, .. } if message == "stop" => Flow::Stop,
+        _ => Flow::Continue,
+    };
+    let chain = Chain::new().listener(Logger("a", log.clone())).middleware(stop).listener(Logger("b", log.clone()));
+    test_feed(chain, &["stop", "go"]);
+    assert_eq!(*log.lock().unwrap(), ["a:stop", "a:go", "b:go"]);
+}
+
+#[test]
+fn test_middleware_modify() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let shout = |_: Arc<Irc>, event: &mut Event| {
+        if let Event::PrivateMsg { ref mut message, .. } = *event {
+            *message = message.to_uppercase();
+        }
+        Flow::Continue
+    };
+    let chain = Chain::new().listener(Logger("a", log.clone())).middleware(shout).listener(Logger("b", log.clone()));
+    test_feed(chain, &["hi"]);
+    assert_eq!(*log.lock().unwrap(), ["a:hi", "b:HI"]);
+}
+
+// Adds a logger on "add" and removes it on "remove".
+#[cfg(test)]
+struct Manager(Arc<Mutex<Vec<String>>>, Option<HandlerId>);
+
+#[cfg(test)]
+impl Listener for Manager {
+    fn private_msg(&mut self, irc: Arc<Irc>, _: &PrefixUser, message: &str) {
+        self.0.lock().unwrap().push(format!("m:{}", message));
+        match message {
+            "add" => self.1 = Some(irc.add_listener(Logger("b", self.0.clone()))),
+            "remove" => irc.remove_handler(self.1.take().unwrap()),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_chain_ops_after_event() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let chain = Chain::new().listener(Manager(log.clone(), None));
+    test_feed(chain, &["add", "x", "remove", "y"]);
+    assert_eq!(*log.lock().unwrap(), ["m:add", "m:x", "b:x", "m:remove", "b:remove", "m:y"]);
+}
@@ -0,0 +1,634 @@
+//! Command router for bots.
+//!
+//! `Commands` is a `Listener` which recognizes commands in channel and private messages,
+//! extracts their arguments and calls the matching handler. Add it to a `Chain`, or give it
+//! directly to `dispatch`.
+//!
+//! ```ignore
+//! let commands = Commands::new(Trigger::Prefix("!".into()))
+//!     .command(Command::new("say", |ctx, args| ctx.reply(args.get("text").unwrap()))
+//!         .rest("text")
+//!         .help("Repeat the given text."))
+//!     .command(Command::new("kick", |ctx, args| {
+//!             let channel = ctx.channel().map(|c| c.name().to_string()).unwrap_or_default();
+//!             ctx.irc().kick(&channel, args.get("nick").unwrap(), args.get("reason"))
+//!         })
+//!         .alias("k")
+//!         .arg("nick")
+//!         .optional_rest("reason")
+//!         .cooldown(Duration::from_secs(10)));
+//! ```
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use loirc::{Code, Message, Prefix, PrefixUser};
+use core::{Channel, DisconnectReason, Error, Irc, IrcWrite};
+use formatting;
+use isupport::CaseMapping;
+use listener::Listener;
+use permissions::{Rule, Subject};
+use replies::Reply;
+
+/// How a message must be written to be recognized as a command.
+///
+/// In private messages, the trigger is optional.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    /// Commands start with a prefix, like `!help`.
+    Prefix(String),
+    /// Commands are addressed to our nickname, like `hiirc: help`.
+    Nickname,
+    /// Commands start with a prefix or are addressed to our nickname.
+    PrefixOrNickname(String),
+}
+
+impl Trigger {
+
+    // Strip the trigger from the text, leaving the command line.
+    fn strip<'t>(&self, text: &'t str, nickname: &str, casemapping: CaseMapping) -> Option<&'t str> {
+        match *self {
+            Trigger::Prefix(ref prefix) => strip_prefix(text, prefix),
+            Trigger::Nickname => strip_nickname(text, nickname, casemapping),
+            Trigger::PrefixOrNickname(ref prefix) => {
+                strip_prefix(text, prefix).or_else(|| strip_nickname(text, nickname, casemapping))
+            }
+        }
+    }
+
+    // How the trigger is shown in the help text.
+    fn display(&self, nickname: &str) -> String {
+        match *self {
+            Trigger::Prefix(ref prefix) | Trigger::PrefixOrNickname(ref prefix) => prefix.clone(),
+            Trigger::Nickname => format!("{}: ", nickname),
+        }
+    }
+
+}
+
+fn strip_prefix<'t>(text: &'t str, prefix: &str) -> Option<&'t str> {
+    match text.strip_prefix(prefix) {
+        Some(line) if !line.is_empty() && !line.starts_with(char::is_whitespace) => Some(line),
+        _ => None,
+    }
+}
+
+fn strip_nickname<'t>(text: &'t str, nickname: &str, casemapping: CaseMapping) -> Option<&'t str> {
+    if nickname.is_empty() || !text.is_char_boundary(nickname.len()) {
+        return None;
+    }
+
+    let (head, tail) = text.split_at(nickname.len());
+    if !casemapping.eq(head, nickname) {
+        return None;
+    }
+
+    let line = match tail.chars().next() {
+        Some(':') | Some(',') | Some(' ') => tail[1..].trim_start(),
+        _ => return None,
+    };
+
+    if line.is_empty() {
+        None
+    } else {
+        Some(line)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ArgSpec {
+    name: String,
+    required: bool,
+    rest: bool,
+}
+
+impl ArgSpec {
+
+    fn usage(&self) -> String {
+        let dots = if self.rest { "..." } else { "" };
+        if self.required {
+            format!("<{}{}>", self.name, dots)
+        } else {
+            format!("[{}{}]", self.name, dots)
+        }
+    }
+
+}
+
+/// Arguments extracted from a command line.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Args {
+    values: Vec<(String, String)>,
+}
+
+impl Args {
+
+    /// Get the value of an argument by name.
+    ///
+    /// Optional arguments which were not given have no value.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.iter().find(|&(n, _)| n == name).map(|(_, v)| &v[..])
+    }
+
+    /// Get the number of arguments which were given.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Check if no argument was given.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+}
+
+// Read a word or a quoted string, returning it along with the remaining input.
+fn next_token(input: &str) -> Option<(String, &str)> {
+    if let Some(quoted) = input.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '\\' => value.push(chars.next()?.1),
+                '"' => return Some((value, &quoted[idx + 1..])),
+                c => value.push(c),
+            }
+        }
+        None
+    } else {
+        let end = input.find(char::is_whitespace).unwrap_or(input.len());
+        Some((input[..end].into(), &input[end..]))
+    }
+}
+
+fn parse_args(specs: &[ArgSpec], line: &str) -> Option<Args> {
+    let mut args = Args::default();
+    let mut rest = line;
+
+    for spec in specs {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            if spec.required {
+                return None;
+            }
+            continue;
+        }
+
+        if spec.rest {
+            args.values.push((spec.name.clone(), rest.trim_end().into()));
+            rest = "";
+        } else {
+            let (value, remaining) = next_token(rest)?;
+            args.values.push((spec.name.clone(), value));
+            rest = remaining;
+        }
+    }
+
+    if rest.trim().is_empty() {
+        Some(args)
+    } else {
+        None
+    }
+}
+
+/// Where a command was sent from, with helpers to reply to it.
+pub struct Context<'c> {
+    irc: Arc<Irc>,
+    channel: Option<&'c str>,
+    sender: &'c PrefixUser,
+    command: &'c str,
+}
+
+impl<'c> Context<'c> {
+
+    /// Get the `Irc` object of the connection.
+    pub fn irc(&self) -> &Arc<Irc> {
+        &self.irc
+    }
+
+    /// Get the channel where the command was sent, if it was not sent in private.
+    pub fn channel(&self) -> Option<Arc<Channel>> {
+        self.channel.and_then(|name| self.irc.channel(name))
+    }
+
+    /// Get the name of the channel where the command was sent, if it was not sent in private.
+    pub fn channel_name(&self) -> Option<&str> {
+        self.channel
+    }
+
+    /// Get the user who sent the command.
+    pub fn sender(&self) -> &PrefixUser {
+        self.sender
+    }
+
+    /// Get the name of the command, as it was typed by the sender.
+    pub fn command(&self) -> &str {
+        self.command
+    }
+
+    /// Reply where the command was sent, in the channel or in private.
+    pub fn reply(&self, text: &str) -> Result<(), Error> {
+        match self.channel {
+            Some(channel) => self.irc.privmsg(channel, text),
+            None => self.irc.privmsg(&self.sender.nickname, text),
+        }
+    }
+
+    /// Reply in a private message to the sender.
+    pub fn reply_private(&self, text: &str) -> Result<(), Error> {
+        self.irc.privmsg(&self.sender.nickname, text)
+    }
+
+    /// Reply in a notice to the sender.
+    pub fn reply_notice(&self, text: &str) -> Result<(), Error> {
+        self.irc.notice(&self.sender.nickname, text)
+    }
+
+}
+
+type Handler = Box<dyn FnMut(&Context, &Args) -> Result<(), Error> + Send>;
+
+/// A command with its arguments and its handler.
+pub struct Command {
+    name: String,
+    aliases: Vec<String>,
+    args: Vec<ArgSpec>,
+    help: String,
+    cooldown: Option<Duration>,
+    last_used: Option<Instant>,
+    rule: Option<Rule>,
+    handler: Handler,
+}
+
+impl Command {
+
+    /// Create a command with the handler called when it's used.
+    pub fn new<F>(name: &str, handler: F) -> Command
+        where F: FnMut(&Context, &Args) -> Result<(), Error> + Send + 'static
+    {
+        Command {
+            name: name.into(),
+            aliases: Vec::new(),
+            args: Vec::new(),
+            help: String::new(),
+            cooldown: None,
+            last_used: None,
+            rule: None,
+            handler: Box::new(handler),
+        }
+    }
+
+    /// Add another name for the command.
+    pub fn alias(mut self, alias: &str) -> Command {
+        self.aliases.push(alias.into());
+        self
+    }
+
+    /// Add a required argument, a word or a quoted string.
+    pub fn arg(mut self, name: &str) -> Command {
+        self.push_arg(name, true, false);
+        self
+    }
+
+    /// Add an optional argument, a word or a quoted string.
+    pub fn optional(mut self, name: &str) -> Command {
+        self.push_arg(name, false, false);
+        self
+    }
+
+    /// Add a required argument containing the rest of the line.
+    ///
+    /// It must be the last argument.
+    pub fn rest(mut self, name: &str) -> Command {
+        self.push_arg(name, true, true);
+        self
+    }
+
+    /// Add an optional argument containing the rest of the line.
+    ///
+    /// It must be the last argument.
+    pub fn optional_rest(mut self, name: &str) -> Command {
+        self.push_arg(name, false, true);
+        self
+    }
+
+    /// Set the description shown by the help command.
+    pub fn help(mut self, help: &str) -> Command {
+        self.help = help.into();
+        self
+    }
+
+    /// Set the minimum delay between two uses of the command.
+    ///
+    /// Uses of the command during the cooldown are ignored.
+    pub fn cooldown(mut self, cooldown: Duration) -> Command {
+        self.cooldown = Some(cooldown);
+        self
+    }
+
+    /// Only allow the users matching the rule to use the command.
+    ///
+    /// The rule is evaluated with the status of the sender in the channel where the command
+    /// was used, and the services account of the sender. When the rule depends on the account
+    /// and `Irc::account` doesn't know it, the command runs after a WHOIS of the sender.
+    pub fn require(mut self, rule: Rule) -> Command {
+        self.rule = Some(rule);
+        self
+    }
+
+    /// Get the name of the command.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the usage of the command, such as `!kick <nick> [reason...]`.
+    pub fn usage(&self, trigger: &str) -> String {
+        let mut usage = format!("{}{}", trigger, self.name);
+        for arg in self.args.iter() {
+            usage.push(' ');
+            usage.push_str(&arg.usage());
+        }
+        usage
+    }
+
+    fn push_arg(&mut self, name: &str, required: bool, rest: bool) {
+        self.args.push(ArgSpec {
+            name: name.into(),
+            required,
+            rest,
+        });
+    }
+
+    fn is_named(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+    }
+
+    fn is_cooling_down(&self, now: Instant) -> bool {
+        match (self.cooldown, self.last_used) {
+            (Some(cooldown), Some(last_used)) => now.duration_since(last_used) < cooldown,
+            _ => false,
+        }
+    }
+
+}
+
+// A command waiting for the WHOIS of its sender, to know their services account.
+struct PendingCommand {
+    sender: PrefixUser,
+    target: String,
+    text: String,
+}
+
+/// A listener routing messages to commands.
+///
+/// When a command requires a rule which depends on the services account of the sender, and
+/// the account is not known by `Irc::account`, the router sends a WHOIS for the sender and
+/// runs the command once the reply is over.
+pub struct Commands {
+    trigger: Trigger,
+    commands: Vec<Command>,
+    help: bool,
+    pending: Vec<PendingCommand>,
+}
+
+impl Commands {
+
+    /// Create a router without any command.
+    ///
+    /// The `help` command is enabled by default.
+    pub fn new(trigger: Trigger) -> Commands {
+        Commands {
+            trigger,
+            commands: Vec::new(),
+            help: true,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Add a command.
+    pub fn command(mut self, command: Command) -> Commands {
+        self.add(command);
+        self
+    }
+
+    /// Enable/disable the automatic `help` command.
+    pub fn help(mut self, help: bool) -> Commands {
+        self.help = help;
+        self
+    }
+
+    /// Add a command.
+    pub fn add(&mut self, command: Command) {
+        self.commands.push(command);
+    }
+
+    /// Handle a message, calling the matching command if there is one.
+    ///
+    /// The `target` is the target of the PRIVMSG, a channel or our nickname. The text should
+    /// not contain formatting codes, see `formatting::strip`.
+    /// Returns true if the message was a command.
+    pub fn handle(&mut self, irc: Arc<Irc>, sender: &PrefixUser, target: &str, text: &str) -> bool {
+        self.route(irc, sender, target, text, false)
+    }
+
+    // Route a command, `whois_done` is true once the WHOIS of the sender is over.
+    fn route(&mut self, irc: Arc<Irc>, sender: &PrefixUser, target: &str, text: &str, whois_done: bool) -> bool {
+        let nickname = irc.nickname();
+        let channel = if irc.is_channel_name(target) { Some(target) } else { None };
+        let line = match self.trigger.strip(text, &nickname, irc.casemapping()) {
+            Some(line) => line,
+            None if channel.is_none() => text,
+            None => return false,
+        };
+
+        let (name, args) = match line.find(char::is_whitespace) {
+            Some(idx) => (&line[..idx], &line[idx..]),
+            None => (line, ""),
+        };
+
+        let ctx = Context {
+            irc: irc.clone(),
+            channel,
+            sender,
+            command: name,
+        };
+
+        if self.help && name.eq_ignore_ascii_case("help") {
+            let _ = self.reply_help(&ctx, &nickname, args.trim());
+            return true;
+        }
+
+        let trigger = self.trigger.display(&nickname);
+        let idx = match self.commands.iter().position(|c| c.is_named(name)) {
+            Some(idx) => idx,
+            None => return false,
+        };
+
+        if let Some(ref rule) = self.commands[idx].rule {
+            let account = irc.account(&sender.nickname);
+            if account.is_none() && !whois_done && rule.needs_account() {
+                self.whois(&irc, sender, target, text);
+                return true;
+            }
+            let status = ctx.channel().and_then(|c| c.user(&sender.nickname)).map(|u| u.status());
+            let subject = Subject::new(sender)
+                .account(account.as_ref().map(|a| &a[..]))
+                .status(status)
+                .casemapping(irc.casemapping());
+            if !rule.allows(&subject) {
+                let _ = ctx.reply_notice("You are not allowed to use this command.");
+                return true;
+            }
+        }
+
+        let command = &mut self.commands[idx];
+        let now = Instant::now();
+        if command.is_cooling_down(now) {
+            return true;
+        }
+
+        match parse_args(&command.args, args) {
+            Some(args) => {
+                command.last_used = Some(now);
+                let _ = (command.handler)(&ctx, &args);
+            }
+            None => {
+                let _ = ctx.reply(&format!("Usage: {}", command.usage(&trigger)));
+            }
+        }
+
+        true
+    }
+
+    fn whois(&mut self, irc: &Irc, sender: &PrefixUser, target: &str, text: &str) {
+        let casemapping = irc.casemapping();
+        let asked = self.pending.iter().any(|p| casemapping.eq(&p.sender.nickname, &sender.nickname));
+        self.pending.push(PendingCommand {
+            sender: sender.clone(),
+            target: target.into(),
+            text: text.into(),
+        });
+        if !asked {
+            let _ = irc.command("WHOIS", &[&sender.nickname], None);
+        }
+    }
+
+    fn reply_help(&self, ctx: &Context, nickname: &str, name: &str) -> Result<(), Error> {
+        let trigger = self.trigger.display(nickname);
+
+        if name.is_empty() {
+            let names: Vec<String> = self.commands.iter().map(|c| format!("{}{}", trigger, c.name)).collect();
+            return ctx.reply(&format!("Commands: {}. Use {}help <command> for details.",
+                                      names.join(", "), trigger));
+        }
+
+        match self.commands.iter().find(|c| c.is_named(name)) {
+            Some(command) => {
+                let mut text = command.usage(&trigger);
+                if !command.help.is_empty() {
+                    text.push_str(" - ");
+                    text.push_str(&command.help);
+                }
+                if !command.aliases.is_empty() {
+                    text.push_str(&format!(" (aliases: {})", command.aliases.join(", ")));
+                }
+                ctx.reply(&text)
+            }
+            None => ctx.reply(&format!("Unknown command: {}", name)),
+        }
+    }
+
+}
+
+impl Listener for Commands {
+
+    fn msg(&mut self, irc: Arc<Irc>, msg: &Message) {
+        if msg.code != Code::Privmsg || msg.args.len() < 2 {
+            return;
+        }
+        let sender = user_or_return!(msg.prefix);
+        let target = some_or_return!(msg.args.first());
+        let text = some_or_return!(msg.args.last());
+        if text.starts_with('\x01') {
+            return;
+        }
+        self.handle(irc, sender, target, &formatting::strip(text));
+    }
+
+    fn disconnect(&mut self, _: Arc<Irc>, _: Option<&DisconnectReason>) {
+        self.pending.clear();
+    }
+
+    fn reply(&mut self, irc: Arc<Irc>, reply: &Reply) {
+        let nickname = match *reply {
+            Reply::EndOfWhois { ref nickname } => nickname,
+            _ => return,
+        };
+        let casemapping = irc.casemapping();
+        let (ready, pending) = self.pending.drain(..).partition(|p| casemapping.eq(&p.sender.nickname, nickname));
+        self.pending = pending;
+        for command in ready {
+            let PendingCommand { sender, target, text } = command;
+            self.route(irc.clone(), &sender, &target, &text, true);
+        }
+    }
+
+}
+
+#[test]
+fn test_strip_prefix() {
+    assert_eq!(strip_prefix("!help me", "!"), Some("help me"));
+    assert_eq!(strip_prefix("! help", "!"), None);
+    assert_eq!(strip_prefix("!", "!"), None);
+    assert_eq!(strip_prefix("help", "!"), None);
+}
+
+#[test]
+fn test_strip_nickname() {
+    let cm = CaseMapping::Rfc1459;
+    assert_eq!(strip_nickname("hiirc: help me", "hiirc", cm), Some("help me"));
+    assert_eq!(strip_nickname("HIIRC, help", "hiirc", cm), Some("help"));
+    assert_eq!(strip_nickname("hiirc help", "hiirc", cm), Some("help"));
+    assert_eq!(strip_nickname("HIIRC[M]: help", "hiirc{m}", cm), Some("help"));
+    assert_eq!(strip_nickname("hiircbot: help", "hiirc", cm), None);
+    assert_eq!(strip_nickname("hiirc:", "hiirc", cm), None);
+}
+
+#[test]
+fn test_parse_args() {
+    let specs = vec![
+        ArgSpec { name: "nick".into(), required: true, rest: false },
+        ArgSpec { name: "channel".into(), required: false, rest: false },
+        ArgSpec { name: "reason".into(), required: false, rest: true },
+    ];
+
+    let args = parse_args(&specs, " bob \"#a b\" go  away ").unwrap();
+    assert_eq!(args.get("nick"), Some("bob"));
+    assert_eq!(args.get("channel"), Some("#a b"));
+    assert_eq!(args.get("reason"), Some("go  away"));
+
+    let args = parse_args(&specs, "bob").unwrap();
+    assert_eq!(args.len(), 1);
+    assert_eq!(args.get("channel"), None);
+
+    assert_eq!(parse_args(&specs, ""), None);
+    assert_eq!(parse_args(&specs, "\"unterminated"), None);
+    assert_eq!(parse_args(&specs[..1], "bob extra"), None);
+}
+
+#[test]
+fn test_next_token_escapes() {
+    assert_eq!(next_token("\"say \\\"hi\\\"\" rest"), Some(("say \"hi\"".into(), " rest")));
+}
+
+#[test]
+fn test_commands_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Commands>();
+}
+
+#[test]
+fn test_usage() {
+    let command = Command::new("kick", |_, _| Ok(())).arg("nick").optional_rest("reason");
+    assert_eq!(command.usage("!"), "!kick <nick> [reason...]");
+}
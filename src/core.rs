@@ -1,14 +1,29 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
-use std::sync::{Arc, Mutex};
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
 use std::fmt::{Display, Formatter};
 use std::fmt;
 use std::error;
-
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use caps::{self, Negotiation};
+use chain::{Chain, ChainOp, HandlerId, Middleware};
+use event::{clone_event, Event};
+use hostmask::{BanStyle, Hostmask};
+use isupport::{CaseMapping, ISupport, ModeChange};
+use lag::{LagMeter, LagSettings};
+use list::{ChannelList, ListEntry, ListFilter, PendingList};
+use presence::{self, Method, Presence};
+use rejoin::Rejoiner;
 use listener::Listener;
 use settings::Settings;
+use replies::Reply;
 use loirc::{self, connect};
-use loirc::{ActivityMonitor, Code, Event, Message, Prefix, Writer};
+use loirc::{ActivityMonitor, Code, Message, Prefix, PrefixUser, Reader, Writer};
+#[cfg(test)]
+use testing::{message, TestServer};
 
 /// Errors that can occur.
 #[derive(Debug)]
@@ -27,6 +42,19 @@ pub enum Error {
     IoError(io::Error),
     /// The message contains a line break.
     Multiline,
+    /// A nickname or channel name can't be sent safely.
+    ///
+    /// It's empty, starts with a colon, or contains a space or a NUL byte.
+    InvalidTarget(String),
+    /// A parameter of a command can't be sent safely.
+    ///
+    /// Only the trailing parameter of a command can contain spaces or start with a colon,
+    /// and no parameter can contain a NUL byte.
+    InvalidParameter(String),
+    /// The server doesn't support a feature, such as quiets.
+    Unsupported(String),
+    /// Two networks of a `MultiClient` have the same name.
+    DuplicateNetwork(String),
 }
 
 impl From<loirc::Error> for Error {
@@ -54,7 +82,11 @@ impl Display for Error {
             Error::Closed => write!(f, "Connection is closed"),
             Error::Disconnected => write!(f, "Client has been disconnected"),
             Error::IoError(ref err) => write!(f, "Client encountered I/O error: {}", err),
-            Error::Multiline => write!(f, "Message contains line break")
+            Error::Multiline => write!(f, "Message contains line break"),
+            Error::InvalidTarget(ref target) => write!(f, "Invalid nickname or channel name: {:?}", target),
+            Error::InvalidParameter(ref param) => write!(f, "Invalid command parameter: {:?}", param),
+            Error::Unsupported(ref feature) => write!(f, "Server does not support {}", feature),
+            Error::DuplicateNetwork(ref network) => write!(f, "Network added twice: {:?}", network),
         }
     }
 }
@@ -67,7 +99,11 @@ impl error::Error for Error {
             Error::Closed => "Connection has been manually closed",
             Error::Disconnected => "Connection has been dropped",
             Error::IoError(ref err) => err.description(),
-            Error::Multiline => "Message contains a line break"
+            Error::Multiline => "Message contains a line break",
+            Error::InvalidTarget(_) => "Invalid nickname or channel name",
+            Error::InvalidParameter(_) => "Invalid command parameter",
+            Error::Unsupported(_) => "Server does not support this feature",
+            Error::DuplicateNetwork(_) => "Network added twice",
         }
     }
 
@@ -90,70 +126,95 @@ pub trait IrcWrite {
     /// If you add a new line it will be refused as a multiline message.
     fn raw<S: AsRef<str>>(&self, raw: S) -> Result<(), Error>;
 
+    /// Features of the server used to build commands, such as the number of modes per MODE.
+    ///
+    /// `Irc` returns the ISUPPORT tokens sent by the server, other writers use the defaults.
+    fn isupport(&self) -> ISupport {
+        ISupport::default()
+    }
+
+    /// Send a command built from its parameters.
+    ///
+    /// Every parameter is checked so that it can't change the meaning of the message: the
+    /// middle parameters can't be empty, start with a colon, or contain spaces or NUL bytes,
+    /// and the trailing parameter can't contain NUL bytes. `Error::InvalidParameter` is
+    /// returned otherwise.
+    fn command(&self, command: &str, params: &[&str], trailing: Option<&str>) -> Result<(), Error> {
+        self.raw(serialize(command, params, trailing)?)
+    }
+
     /// NICK command.
     fn nick(&self, nickname: &str) -> Result<(), Error> {
-        self.raw(format!("NICK {}", nickname))
+        check_target(nickname)?;
+        self.command("NICK", &[nickname], None)
     }
 
     /// USER command.
     fn user(&self, username: &str, realname: &str) -> Result<(), Error> {
-        self.raw(format!("USER {} 8 * :{}", username, realname))
+        self.command("USER", &[username, "8", "*"], Some(realname))
     }
 
     /// PING command.
     fn ping(&self, server: &str) -> Result<(), Error> {
-        self.raw(format!("PING {}", server))
+        self.command("PING", &[], Some(server))
     }
 
     /// PONG command.
     fn pong(&self, server: &str) -> Result<(), Error> {
-        self.raw(format!("PONG {}", server))
+        self.command("PONG", &[], Some(server))
     }
 
     /// PASS command.
     fn pass(&self, password: &str) -> Result<(), Error> {
-        self.raw(format!("PASS {}", password))
+        self.command("PASS", &[], Some(password))
     }
 
     /// PRIVMSG command.
     fn privmsg(&self, target: &str, text: &str) -> Result<(), Error> {
-        self.raw(format!("PRIVMSG {} :{}", target, text))
+        check_target(target)?;
+        self.command("PRIVMSG", &[target], Some(text))
     }
 
     /// NOTICE command.
     fn notice(&self, target: &str, text: &str) -> Result<(), Error> {
-        self.raw(format!("NOTICE {} :{}", target, text))
+        check_target(target)?;
+        self.command("NOTICE", &[target], Some(text))
     }
 
     /// JOIN command.
     fn join(&self, channel: &str, password: Option<&str>) -> Result<(), Error> {
+        check_target(channel)?;
         match password {
-            None => self.raw(format!("JOIN {}", channel)),
-            Some(password) => self.raw(format!("JOIN {} {}", channel, password)),
+            None => self.command("JOIN", &[channel], None),
+            Some(password) => self.command("JOIN", &[channel, password], None),
         }
     }
 
     /// PART command.
     fn part(&self, channel: &str, message: Option<&str>) -> Result<(), Error> {
-        match message {
-            None => self.raw(format!("PART {}", channel)),
-            Some(message) => self.raw(format!("PART {} :{}", channel, message)),
-        }
+        check_target(channel)?;
+        self.command("PART", &[channel], message)
     }
 
     /// QUIT command.
     fn quit(&self, message: Option<&str>) -> Result<(), Error> {
-        match message {
-            None => self.raw(format!("QUIT :No message")),
-            Some(message) => self.raw(format!("QUIT :{}", message)),
-        }
+        self.command("QUIT", &[], Some(message.unwrap_or("No message")))
+    }
+
+    /// AWAY command.
+    ///
+    /// With a message we are marked as away, without one we are back. `Irc` sets the
+    /// message again after a reconnection.
+    fn away(&self, message: Option<&str>) -> Result<(), Error> {
+        self.command("AWAY", &[], message)
     }
 
     /// Retrive the topic of a given channel.
     ///
     /// The `topic` event will receive the information.
     fn get_topic(&self, channel: &str) -> Result<(), Error> {
-        self.raw(format!("TOPIC {}", channel))
+        check_target(channel)?;
+        self.command("TOPIC", &[channel], None)
     }
 
     /// Set the topic of a channel.
@@ -161,18 +222,300 @@ pub trait IrcWrite {
     /// To remove the topic of a channel, use an empty topic string.
     /// It will also trigger a `topic_change` event.
     fn set_topic(&self, channel: &str, topic: &str) -> Result<(), Error> {
-        self.raw(format!("TOPIC {} :{}", channel, topic))
+        check_target(channel)?;
+        self.command("TOPIC", &[channel], Some(topic))
     }
 
     /// KICK command.
-    fn kick(&self, channel: &str, nickname: &str) -> Result<(), Error> {
-        self.raw(format!("KICK {} {}", channel, nickname))
+    fn kick(&self, channel: &str, nickname: &str, reason: Option<&str>) -> Result<(), Error> {
+        check_target(channel)?;
+        check_target(nickname)?;
+        self.command("KICK", &[channel, nickname], reason)
+    }
+
+    /// MODE command.
+    ///
+    /// The modes are sent as is, use `change_modes` to change many modes of a channel.
+    fn mode(&self, target: &str, modes: &str, params: &[&str]) -> Result<(), Error> {
+        check_target(target)?;
+        let mut all = vec![target, modes];
+        all.extend_from_slice(params);
+        self.command("MODE", &all, None)
+    }
+
+    /// Change several modes of a channel.
+    ///
+    /// The changes are sent in as few MODE commands as possible, each with at most the
+    /// number of modes the server accepts, `MODES`.
+    fn change_modes(&self, channel: &str, changes: &[ModeChange]) -> Result<(), Error> {
+        check_target(channel)?;
+        for (modes, params) in mode_batches(changes, self.isupport().max_modes()) {
+            let params: Vec<&str> = params.iter().map(|p| &p[..]).collect();
+            self.mode(channel, &modes, &params)?;
+        }
+        Ok(())
+    }
+
+    /// Give operator status to users of a channel.
+    fn op(&self, channel: &str, nicknames: &[&str]) -> Result<(), Error> {
+        self.change_modes(channel, &mode_changes(true, 'o', nicknames))
+    }
+
+    /// Take operator status from users of a channel.
+    fn deop(&self, channel: &str, nicknames: &[&str]) -> Result<(), Error> {
+        self.change_modes(channel, &mode_changes(false, 'o', nicknames))
+    }
+
+    /// Give half operator status to users of a channel.
+    fn halfop(&self, channel: &str, nicknames: &[&str]) -> Result<(), Error> {
+        self.change_modes(channel, &mode_changes(true, 'h', nicknames))
+    }
+
+    /// Take half operator status from users of a channel.
+    fn dehalfop(&self, channel: &str, nicknames: &[&str]) -> Result<(), Error> {
+        self.change_modes(channel, &mode_changes(false, 'h', nicknames))
+    }
+
+    /// Give voice to users of a channel.
+    fn voice(&self, channel: &str, nicknames: &[&str]) -> Result<(), Error> {
+        self.change_modes(channel, &mode_changes(true, 'v', nicknames))
+    }
+
+    /// Take voice from users of a channel.
+    fn devoice(&self, channel: &str, nicknames: &[&str]) -> Result<(), Error> {
+        self.change_modes(channel, &mode_changes(false, 'v', nicknames))
+    }
+
+    /// Ban masks from a channel.
+    ///
+    /// Incomplete masks are completed with wildcards, so `alice` bans `alice!*@*`. Extended
+    /// bans such as `$a:account` are sent as they are.
+    fn ban(&self, channel: &str, masks: &[&str]) -> Result<(), Error> {
+        let masks: Vec<String> = masks.iter().map(|m| Hostmask::parse(m).to_string()).collect();
+        let masks: Vec<&str> = masks.iter().map(|m| &m[..]).collect();
+        self.change_modes(channel, &mode_changes(true, 'b', &masks))
+    }
+
+    /// Remove masks from the ban list of a channel.
+    ///
+    /// Incomplete masks are completed with wildcards, like with `ban`.
+    fn unban(&self, channel: &str, masks: &[&str]) -> Result<(), Error> {
+        let masks: Vec<String> = masks.iter().map(|m| Hostmask::parse(m).to_string()).collect();
+        let masks: Vec<&str> = masks.iter().map(|m| &m[..]).collect();
+        self.change_modes(channel, &mode_changes(false, 'b', &masks))
+    }
+
+    /// Ban a user with a mask of the given style, then kick them.
+    fn kickban(&self, channel: &str, user: &PrefixUser, style: BanStyle, reason: Option<&str>) -> Result<(), Error> {
+        let mask = Hostmask::ban_mask(user, style).to_string();
+        self.change_modes(channel, &mode_changes(true, 'b', &[&mask]))?;
+        self.kick(channel, &user.nickname, reason)
+    }
+
+    /// Prevent masks from talking in a channel.
+    ///
+    /// Servers either have a `q` list mode or a `q` extended ban, other servers return
+    /// `Error::Unsupported`. Incomplete masks are completed with wildcards, like with `ban`.
+    fn quiet(&self, channel: &str, masks: &[&str]) -> Result<(), Error> {
+        self.change_modes(channel, &quiet_changes(&self.isupport(), true, masks)?)
+    }
+
+    /// Remove masks from the quiet list of a channel.
+    fn unquiet(&self, channel: &str, masks: &[&str]) -> Result<(), Error> {
+        self.change_modes(channel, &quiet_changes(&self.isupport(), false, masks)?)
+    }
+
+    /// Set or remove the key of a channel.
+    fn set_key(&self, channel: &str, key: Option<&str>) -> Result<(), Error> {
+        match key {
+            Some(key) => self.change_modes(channel, &mode_changes(true, 'k', &[key])),
+            // Most servers need a parameter to remove the key, but don't check it.
+            None => self.change_modes(channel, &mode_changes(false, 'k', &["*"])),
+        }
+    }
+
+    /// Set or remove the user limit of a channel.
+    fn set_limit(&self, channel: &str, limit: Option<u32>) -> Result<(), Error> {
+        match limit {
+            Some(limit) => self.change_modes(channel, &mode_changes(true, 'l', &[&limit.to_string()])),
+            None => self.change_modes(channel, &[ModeChange { set: false, mode: 'l', param: None }]),
+        }
+    }
+
+    /// LIST command.
+    ///
+    /// The conditions of the filter are sent along, if there's one. `Irc` only sends the
+    /// conditions supported by the server and applies the limits of the filter. Each channel
+    /// is given to `Listener::list_entry` and the whole list to `Listener::list_end`.
+    fn list(&self, filter: Option<&ListFilter>) -> Result<(), Error> {
+        match filter.and_then(|f| f.param(None)) {
+            None => self.command("LIST", &[], None),
+            Some(param) => self.command("LIST", &[&param], None),
+        }
+    }
+
+    /// INVITE command.
+    fn invite(&self, nickname: &str, channel: &str) -> Result<(), Error> {
+        check_target(nickname)?;
+        check_target(channel)?;
+        self.command("INVITE", &[nickname, channel], None)
+    }
+
+    /// Retrieve the ban list of a channel.
+    ///
+    /// Once received, the list is available with `Channel::bans`.
+    fn get_bans(&self, channel: &str) -> Result<(), Error> {
+        check_target(channel)?;
+        self.command("MODE", &[channel, "+b"], None)
+    }
+
+    /// Retrieve the invite list of a channel.
+    ///
+    /// Once received, the list is available with `Channel::invites`. `Irc` retrieves it by
+    /// itself when we become operator of a channel.
+    fn get_invites(&self, channel: &str) -> Result<(), Error> {
+        check_target(channel)?;
+        self.command("MODE", &[channel, "+I"], None)
     }
 
 }
 
-/// Status of a user inside a channel.
+// Check that a nickname or channel name can be sent as a single parameter.
+fn check_target(target: &str) -> Result<(), Error> {
+    if target.is_empty() || target.starts_with(':') || target.contains([' ', '\0']) {
+        return Err(Error::InvalidTarget(target.into()));
+    }
+    Ok(())
+}
+
+// Build a message, making sure each parameter stays a single parameter.
+//
+// Line breaks are left to `IrcWrite::raw`, which refuses them.
+fn serialize(command: &str, params: &[&str], trailing: Option<&str>) -> Result<String, Error> {
+    let mut message = String::from(command);
+
+    for param in params {
+        if param.is_empty() || param.starts_with(':') || param.contains([' ', '\0']) {
+            return Err(Error::InvalidParameter(param.to_string()));
+        }
+        message.push(' ');
+        message.push_str(param);
+    }
+
+    if let Some(trailing) = trailing {
+        if trailing.contains('\0') {
+            return Err(Error::InvalidParameter(trailing.into()));
+        }
+        message.push_str(" :");
+        message.push_str(trailing);
+    }
+
+    Ok(message)
+}
+
+/// Reasons the server refused to let us join a channel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JoinFailure {
+    /// The channel reached its user limit, ERR_CHANNELISFULL (471).
+    Full,
+    /// The channel is invite only, ERR_INVITEONLYCHAN (473).
+    InviteOnly,
+    /// We are banned from the channel, ERR_BANNEDFROMCHAN (474).
+    Banned,
+    /// The channel key is missing or wrong, ERR_BADCHANNELKEY (475).
+    BadKey,
+    /// Only users logged in with services can join, ERR_NEEDREGGEDNICK (477).
+    RegisteredOnly,
+    /// The channel name is not valid, ERR_NOSUCHCHANNEL (403).
+    NoSuchChannel,
+    /// We are in too many channels already, ERR_TOOMANYCHANNELS (405).
+    TooManyChannels,
+}
+
+impl JoinFailure {
+
+    /// Get the reason matching an error code, if there's one.
+    pub fn from_code(code: &Code) -> Option<JoinFailure> {
+        match *code {
+            Code::ErrChannelisfull => Some(JoinFailure::Full),
+            Code::ErrInviteonlychan => Some(JoinFailure::InviteOnly),
+            Code::ErrBannedfromchan => Some(JoinFailure::Banned),
+            Code::ErrBadchannelkey => Some(JoinFailure::BadKey),
+            Code::ErrNochanmodes => Some(JoinFailure::RegisteredOnly),
+            Code::ErrNosuchchannel => Some(JoinFailure::NoSuchChannel),
+            Code::ErrToomanychannels => Some(JoinFailure::TooManyChannels),
+            _ => None,
+        }
+    }
+
+}
+
+/// Kinds of reasons the server gives for closing the connection.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisconnectKind {
+    /// We are banned from the server, with a K-line, G-line or similar.
+    KLined,
+    /// We sent too much data, such as `Excess Flood`.
+    Flood,
+    /// We didn't answer a PING in time.
+    PingTimeout,
+    /// We didn't finish the registration in time.
+    RegistrationTimeout,
+    /// We reconnected too fast or too often.
+    Throttled,
+    /// Any other reason.
+    Other,
+}
+
+/// Reason given by the server in an ERROR message before closing the connection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DisconnectReason {
+    /// Kind of reason, guessed from the message.
+    pub kind: DisconnectKind,
+    /// Text of the ERROR message, like `Closing Link: host (Excess Flood)`.
+    pub message: String,
+}
+
+impl DisconnectReason {
+
+    /// Guess the kind of reason from the text of an ERROR message.
+    ///
+    /// Servers don't agree on the wording, so the usual ones are recognized and the other
+    /// messages are `DisconnectKind::Other`. The text of a quit message is written by the user,
+    /// so it's always `DisconnectKind::Other`.
+    pub fn parse(message: &str) -> DisconnectReason {
+        let text = message.to_lowercase();
+        let has = |words: &[&str]| words.iter().any(|w| text.contains(w));
+
+        let kind = if has(&["(quit:", "[quit:", "(\"quit:"]) {
+            DisconnectKind::Other
+        } else if has(&["k-line", "g-line", "z-line", "d-line", "kline", "gline", "zline", "dline", "akill",
+                        "banned from this server"]) {
+            DisconnectKind::KLined
+        } else if has(&["flood", "sendq"]) {
+            DisconnectKind::Flood
+        } else if has(&["ping timeout"]) {
+            DisconnectKind::PingTimeout
+        } else if has(&["registration timeout", "registration timed out"]) {
+            DisconnectKind::RegistrationTimeout
+        } else if has(&["throttl", "too fast", "too many connections"]) {
+            DisconnectKind::Throttled
+        } else {
+            DisconnectKind::Other
+        };
+
+        DisconnectReason {
+            kind,
+            message: message.into(),
+        }
+    }
+
+}
+
+/// Status of a user inside a channel.
+///
+/// Statuses are ordered from the lowest to the highest.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum ChannelUserStatus {
     /// User has special status.
     Normal,
@@ -246,6 +589,17 @@ impl ChannelUser {
 
 }
 
+/// Entry of a channel's ban list or invite list.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Ban {
+    /// Banned mask.
+    pub mask: Hostmask,
+    /// Who set the ban, if known.
+    pub set_by: Option<String>,
+    /// When the ban was set, in seconds since the Unix epoch, if known.
+    pub set_at: Option<u64>,
+}
+
 /// Channel
 #[derive(Debug)]
 pub struct Channel {
@@ -254,15 +608,36 @@ pub struct Channel {
     name: String,
     /// Topic of the channel.
     topic: Mutex<Option<Arc<String>>>,
+    /// Ban list of the channel.
+    bans: Mutex<Vec<Ban>>,
+    /// Ban list being received from the server.
+    pending_bans: Mutex<Option<Vec<Ban>>>,
+    /// Invite list of the channel.
+    invites: Mutex<Vec<Ban>>,
+    /// Invite list being received from the server.
+    pending_invites: Mutex<Option<Vec<Ban>>>,
+    /// List of users being received from the server.
+    pending_names: Mutex<Option<Vec<Arc<ChannelUser>>>>,
+    /// Whether the users are out of date since a disconnection.
+    desynced: Mutex<bool>,
+    /// Case mapping used to compare nicknames.
+    casemapping: CaseMapping,
 }
 
 impl Channel {
 
-    fn new(name: &str) -> Channel {
+    fn new(name: &str, casemapping: CaseMapping) -> Channel {
         Channel {
             users: Mutex::new(Vec::new()),
             name: name.into(),
             topic: Mutex::new(None),
+            bans: Mutex::new(Vec::new()),
+            pending_bans: Mutex::new(None),
+            invites: Mutex::new(Vec::new()),
+            pending_invites: Mutex::new(None),
+            pending_names: Mutex::new(None),
+            desynced: Mutex::new(false),
+            casemapping,
         }
     }
 
@@ -281,7 +656,7 @@ impl Channel {
         let users = self.users.lock().unwrap();
 
         for user in users.iter() {
-            if *user.nickname() == nickname {
+            if self.casemapping.eq(&user.nickname(), nickname) {
                 return Some(user.clone());
             }
         }
@@ -294,18 +669,128 @@ impl Channel {
         self.users.lock().unwrap().clone()
     }
 
+    /// Check if the channel is out of date.
+    ///
+    /// Channels are kept across a reconnection, with the users they had when the connection
+    /// was lost. They are synchronized again once we rejoin them and the server sends the
+    /// list of users. The channels we could not rejoin are dropped once the server has
+    /// answered the JOIN commands sent after registering.
+    pub fn is_desynced(&self) -> bool {
+        *self.desynced.lock().unwrap()
+    }
+
+    /// Get the ban list of this channel.
+    ///
+    /// The list is kept up to date with the MODE messages seen in the channel. The full list
+    /// is only known after requesting it with `IrcWrite::get_bans`.
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.lock().unwrap().clone()
+    }
+
+    /// Check if a user matches one of the bans of this channel.
+    pub fn is_banned(&self, user: &PrefixUser) -> bool {
+        self.bans.lock().unwrap().iter().any(|b| b.mask.matches_user(user, self.casemapping))
+    }
+
+    /// Get the invite list of this channel.
+    ///
+    /// Users matching the invite list can join the channel when it's invite only. The list
+    /// is requested once we are operator, and kept up to date with the MODE messages.
+    pub fn invites(&self) -> Vec<Ban> {
+        self.invites.lock().unwrap().clone()
+    }
+
+    /// Check if a user matches one of the entries of the invite list of this channel.
+    pub fn is_invited(&self, user: &PrefixUser) -> bool {
+        self.invites.lock().unwrap().iter().any(|i| i.mask.matches_user(user, self.casemapping))
+    }
+
+    fn add_ban(&self, ban: Ban) {
+        let mut bans = self.bans.lock().unwrap();
+        if !bans.iter().any(|b| b.mask.eq_mapped(&ban.mask, self.casemapping)) {
+            bans.push(ban);
+        }
+    }
+
+    fn remove_ban(&self, mask: &Hostmask) {
+        self.bans.lock().unwrap().retain(|b| !b.mask.eq_mapped(mask, self.casemapping));
+    }
+
+    fn add_pending_ban(&self, ban: Ban) {
+        self.pending_bans.lock().unwrap().get_or_insert_with(Vec::new).push(ban);
+    }
+
+    fn finish_bans(&self) {
+        let bans = self.pending_bans.lock().unwrap().take().unwrap_or_default();
+        *self.bans.lock().unwrap() = bans;
+    }
+
+    fn add_invite(&self, invite: Ban) {
+        let mut invites = self.invites.lock().unwrap();
+        if !invites.iter().any(|i| i.mask.eq_mapped(&invite.mask, self.casemapping)) {
+            invites.push(invite);
+        }
+    }
+
+    fn remove_invite(&self, mask: &Hostmask) {
+        self.invites.lock().unwrap().retain(|i| !i.mask.eq_mapped(mask, self.casemapping));
+    }
+
+    fn add_pending_invite(&self, invite: Ban) {
+        self.pending_invites.lock().unwrap().get_or_insert_with(Vec::new).push(invite);
+    }
+
+    fn finish_invites(&self) {
+        let invites = self.pending_invites.lock().unwrap().take().unwrap_or_default();
+        *self.invites.lock().unwrap() = invites;
+    }
+
     fn add_user(&self, user: Arc<ChannelUser>) {
         self.users.lock().unwrap().push(user);
     }
 
+    fn desync(&self) {
+        *self.desynced.lock().unwrap() = true;
+        *self.pending_names.lock().unwrap() = None;
+    }
+
+    fn add_pending_name(&self, user: ChannelUser) {
+        self.pending_names.lock().unwrap().get_or_insert_with(Vec::new).push(Arc::new(user));
+    }
+
+    /// Replace the users with the list received from the server.
+    ///
+    /// The users who are still in the channel keep their ChannelUser object. Returns the
+    /// users who joined and the users who left since the previous list.
+    fn finish_names(&self) -> (Vec<Arc<ChannelUser>>, Vec<Arc<ChannelUser>>) {
+        let names = self.pending_names.lock().unwrap().take().unwrap_or_default();
+        let mut users = self.users.lock().unwrap();
+        let mut joined = Vec::new();
+        let mut present = Vec::with_capacity(names.len());
+
+        for user in names {
+            match users.iter().position(|u| self.casemapping.eq(&u.nickname(), &user.nickname())) {
+                Some(pos) => {
+                    let old = users.remove(pos);
+                    old.set_status(user.status());
+                    present.push(old);
+                }
+                None => {
+                    joined.push(user.clone());
+                    present.push(user);
+                }
+            }
+        }
+
+        *self.desynced.lock().unwrap() = false;
+        (joined, mem::replace(&mut *users, present))
+    }
+
     fn remove_user(&self, nickname: &str) -> Option<Arc<ChannelUser>> {
         let mut users = self.users.lock().unwrap();
 
-        if let Some(pos) = users.iter().position(|u| *u.nickname() == nickname) {
-            Some(users.remove(pos))
-        } else {
-            None
-        }
+        let pos = users.iter().position(|u| self.casemapping.eq(&u.nickname(), nickname))?;
+        Some(users.remove(pos))
     }
 
     fn set_topic(&self, topic: &str) {
@@ -318,38 +803,308 @@ impl Channel {
 
 }
 
+/// Information about the server, from its RPL_MYINFO reply.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerInfo {
+    /// Name of the server.
+    pub name: String,
+    /// Version of the server software.
+    pub version: String,
+    /// User modes supported by the server.
+    pub user_modes: String,
+    /// Channel modes supported by the server.
+    pub channel_modes: String,
+}
+
 /// Status of the connection.
-#[derive(Clone, Copy, Debug)]
+///
+/// Once connected, the status follows the registration: it moves on as the client sends
+/// `CAP`, `AUTHENTICATE`, `NICK` and `USER`, and is `Registered` once the server sends its
+/// welcome. Until then, the commands written with `IrcWrite` are queued, except the ones used
+/// to register. The queued commands are dropped if the connection is lost.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum ConnectionStatus {
     /// Connection was closed.
-    Closed(&'static str),
-    /// Connection is alive.
-    Connected,
+    Closed(String),
+    /// Connection is open, the registration didn't start yet.
+    Connecting,
+    /// Capabilities are being negotiated with `CAP`, see the `caps` module.
+    NegotiatingCaps,
+    /// Authenticating with SASL, during the capability negotiation.
+    Authenticating,
+    /// `NICK` and `USER` were sent, waiting for the welcome of the server.
+    Registering,
+    /// The server accepted the registration.
+    Registered,
     /// Connection was dropped.
     Disconnected,
     /// Attempting to reconnect.
     Reconnecting,
 }
 
+impl ConnectionStatus {
+
+    /// Check if the connection is open but not registered yet.
+    pub fn is_registering(&self) -> bool {
+        matches!(*self, ConnectionStatus::Connecting
+            | ConnectionStatus::NegotiatingCaps
+            | ConnectionStatus::Authenticating
+            | ConnectionStatus::Registering)
+    }
+
+    // Status after sending a command during the registration.
+    fn after_sending(&self, command: &str, params: &str) -> Option<ConnectionStatus> {
+        let subcommand = params.split(' ').next().unwrap_or("").to_ascii_uppercase();
+        match (self, command, &subcommand[..]) {
+            (_, "CAP", "END") => Some(ConnectionStatus::Registering),
+            (&ConnectionStatus::Connecting, "CAP", "LS") | (&ConnectionStatus::Connecting, "CAP", "REQ")
+            | (&ConnectionStatus::Registering, "CAP", "LS") | (&ConnectionStatus::Registering, "CAP", "REQ") => {
+                Some(ConnectionStatus::NegotiatingCaps)
+            }
+            (&ConnectionStatus::NegotiatingCaps, "AUTHENTICATE", _) => Some(ConnectionStatus::Authenticating),
+            (&ConnectionStatus::Connecting, "NICK", _) | (&ConnectionStatus::Connecting, "USER", _) => {
+                Some(ConnectionStatus::Registering)
+            }
+            _ => None,
+        }
+    }
+
+}
+
+// Times of the last transitions of the status, and reconnection counters.
+#[derive(Debug, Default)]
+struct StatusHistory {
+    connected_since: Option<Instant>,
+    disconnected_since: Option<Instant>,
+    reconnect_attempts: u32,
+    reconnections: u32,
+}
+
+// Commands which can be sent before the registration is complete.
+const REGISTRATION_COMMANDS: &[&str] = &["PASS", "CAP", "AUTHENTICATE", "NICK", "USER", "PING", "PONG", "QUIT"];
+
 /// Contains the connection to the server and the data about channels and users.
 pub struct Irc {
     writer: Writer,
     channels: Mutex<HashMap<String, Arc<Channel>>>,
     status: Mutex<ConnectionStatus>,
+    status_changed: Condvar,
+    history: Mutex<StatusHistory>,
+    chain_ops: Mutex<Vec<ChainOp>>,
+    network: String,
+    nickname: Mutex<Arc<String>>,
+    accounts: Mutex<HashMap<String, String>>,
+    isupport: Mutex<ISupport>,
+    server_info: Mutex<Option<ServerInfo>>,
+    motd: Mutex<Option<Arc<String>>>,
+    list: Mutex<Option<PendingList>>,
+    keys: Mutex<HashMap<String, String>>,
+    pending_joins: Mutex<HashSet<String>>,
+    away_message: Mutex<Option<Arc<String>>>,
+    away: Mutex<bool>,
+    presence: Mutex<Presence>,
+    disconnect_reason: Mutex<Option<DisconnectReason>>,
+    lag: Mutex<Option<LagMeter>>,
+    caps: Mutex<Vec<String>>,
+    // Commands written before the registration was complete.
+    queue: Mutex<Vec<String>>,
+    // Number of the current connection, to tell the timeouts of older connections apart.
+    connection: Mutex<u64>,
 }
 
 impl Irc {
 
     /// Get a channel by name.
     pub fn channel(&self, name: &str) -> Option<Arc<Channel>> {
-        self.get_channel_by_id(&name.to_lowercase())
+        self.get_channel_by_id(&self.normalize(name))
     }
 
     /// Get the list of channels.
+    ///
+    /// When `Settings::rejoin` is enabled, the channels are kept across a reconnection and
+    /// are desynced until we rejoin them, see `Channel::is_desynced`.
     pub fn channels(&self) -> Vec<Arc<Channel>> {
         self.channels.lock().unwrap().values().map(|v| v.clone()).collect::<Vec<Arc<Channel>>>()
     }
 
+    /// Get our current nickname.
+    ///
+    /// This is the preferred nickname from the settings until the server confirms it.
+    pub fn nickname(&self) -> Arc<String> {
+        self.nickname.lock().unwrap().clone()
+    }
+
+    /// Check if the given nickname is our own.
+    pub fn is_me(&self, nickname: &str) -> bool {
+        self.casemapping().eq(&self.nickname(), nickname)
+    }
+
+    /// Get the services account a user is logged in as, if it's known.
+    ///
+    /// Accounts are learned from WHOIS replies and from `ACCOUNT` messages, when the
+    /// server sends them.
+    pub fn account(&self, nickname: &str) -> Option<String> {
+        self.accounts.lock().unwrap().get(&self.normalize(nickname)).cloned()
+    }
+
+    /// Get the features advertised by the server.
+    pub fn isupport(&self) -> ISupport {
+        self.isupport.lock().unwrap().clone()
+    }
+
+    /// Check if a name is a channel name, using the channel types of the server.
+    pub fn is_channel_name(&self, name: &str) -> bool {
+        self.isupport.lock().unwrap().is_channel(name)
+    }
+
+    /// Get the case mapping used by the server.
+    pub fn casemapping(&self) -> CaseMapping {
+        self.isupport.lock().unwrap().casemapping()
+    }
+
+    // Key of a nickname or channel name in the maps of the client.
+    pub(crate) fn normalize(&self, name: &str) -> String {
+        self.casemapping().normalize(name)
+    }
+
+    /// Get the name, version and supported modes of the server, once it sent them.
+    pub fn server_info(&self) -> Option<ServerInfo> {
+        self.server_info.lock().unwrap().clone()
+    }
+
+    /// Get the message of the day of the server, once it's fully received.
+    ///
+    /// Lines are separated by `\n`.
+    pub fn motd(&self) -> Option<Arc<String>> {
+        self.motd.lock().unwrap().clone()
+    }
+
+    /// Check if the server marked us as away.
+    pub fn is_away(&self) -> bool {
+        *self.away.lock().unwrap()
+    }
+
+    /// Get the capabilities enabled by the server on this connection.
+    pub fn caps(&self) -> Vec<String> {
+        self.caps.lock().unwrap().clone()
+    }
+
+    /// Check if a capability is enabled on this connection.
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.lock().unwrap().iter().any(|c| c.eq_ignore_ascii_case(cap))
+    }
+
+    /// Get the away message we set with `IrcWrite::away`.
+    ///
+    /// It's kept across reconnections, while `is_away` follows what the server says.
+    pub fn away_message(&self) -> Option<Arc<String>> {
+        self.away_message.lock().unwrap().clone()
+    }
+
+    /// Get the status of the connection.
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Get when the current connection was opened, `None` while disconnected.
+    pub fn connected_since(&self) -> Option<Instant> {
+        self.history.lock().unwrap().connected_since
+    }
+
+    /// Get when the connection was lost, `None` while connected.
+    pub fn disconnected_since(&self) -> Option<Instant> {
+        self.history.lock().unwrap().disconnected_since
+    }
+
+    /// Get the number of attempts to reconnect since the connection was lost.
+    ///
+    /// It's reset once the registration of the new connection is complete.
+    pub fn reconnect_attempts(&self) -> u32 {
+        self.history.lock().unwrap().reconnect_attempts
+    }
+
+    /// Get the number of times the connection was restored.
+    pub fn reconnections(&self) -> u32 {
+        self.history.lock().unwrap().reconnections
+    }
+
+    /// Block until the status of the connection matches the condition, returns that status.
+    ///
+    /// Returns `None` if the timeout expires first, or if the connection is closed without
+    /// matching the condition.
+    ///
+    /// ```ignore
+    /// if irc.wait_status(|s| *s == ConnectionStatus::Registered, Some(Duration::from_secs(30))).is_some() {
+    ///     irc.privmsg("#hiirc", "I'm back")?;
+    /// }
+    /// ```
+    pub fn wait_status<F>(&self, condition: F, timeout: Option<Duration>) -> Option<ConnectionStatus>
+        where F: Fn(&ConnectionStatus) -> bool
+    {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut status = self.status.lock().unwrap();
+        loop {
+            if condition(&status) {
+                return Some(status.clone());
+            }
+            if let ConnectionStatus::Closed(_) = *status {
+                return None;
+            }
+            status = match deadline {
+                None => self.status_changed.wait(status).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.status_changed.wait_timeout(status, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Block until the registration is complete, returns false if it doesn't happen in time.
+    pub fn wait_registered(&self, timeout: Option<Duration>) -> bool {
+        self.wait_status(|s| *s == ConnectionStatus::Registered, timeout).is_some()
+    }
+
+    /// Get the average lag of the connection, see the `lag` module.
+    ///
+    /// Returns `None` if the lag measurement is disabled, or until the first measure.
+    pub fn lag(&self) -> Option<Duration> {
+        self.lag.lock().unwrap().as_ref().and_then(|meter| meter.lag())
+    }
+
+    /// Get the reason the server gave when it last closed the connection, if it gave one.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.disconnect_reason.lock().unwrap().clone()
+    }
+
+    /// Add nicknames to the watch list, see the `presence` module.
+    pub fn watch(&self, nicknames: &[&str]) -> Result<(), Error> {
+        self.watch_with(self, nicknames)
+    }
+
+    /// Remove nicknames from the watch list.
+    pub fn unwatch(&self, nicknames: &[&str]) -> Result<(), Error> {
+        self.unwatch_with(self, nicknames)
+    }
+
+    /// Get the watched nicknames.
+    pub fn watched(&self) -> Vec<String> {
+        self.presence.lock().unwrap().watched().to_vec()
+    }
+
+    /// Check if a watched nickname is online, if it's known.
+    pub fn is_online(&self, nickname: &str) -> Option<bool> {
+        self.presence.lock().unwrap().is_online(nickname, self.casemapping())
+    }
+
+    /// Get the name of the network this connection belongs to.
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
     /// Check if the underlying connection is closed.
     pub fn is_closed(&self) -> bool {
         self.writer.is_closed()
@@ -361,20 +1116,77 @@ impl Irc {
         Ok(())
     }
 
-    fn new(writer: Writer) -> Irc {
+    /// Add a listener at the end of the dispatcher's chain.
+    ///
+    /// The listener is added once the current event has gone through the chain.
+    pub fn add_listener<L>(&self, listener: L) -> HandlerId
+        where L: Listener + Send + 'static
+    {
+        let id = HandlerId::next();
+        self.chain_ops.lock().unwrap().push(ChainOp::AddListener(id, Box::new(listener)));
+        id
+    }
+
+    /// Add a middleware at the end of the dispatcher's chain.
+    ///
+    /// The middleware is added once the current event has gone through the chain.
+    pub fn add_middleware<M>(&self, middleware: M) -> HandlerId
+        where M: Middleware + Send + 'static
+    {
+        let id = HandlerId::next();
+        self.chain_ops.lock().unwrap().push(ChainOp::AddMiddleware(id, Box::new(middleware)));
+        id
+    }
+
+    /// Remove a listener or a middleware from the dispatcher's chain.
+    ///
+    /// The handler is removed once the current event has gone through the chain.
+    pub fn remove_handler(&self, id: HandlerId) {
+        self.chain_ops.lock().unwrap().push(ChainOp::Remove(id));
+    }
+
+    fn new(writer: Writer, network: &str, nickname: &str, lag: Option<LagSettings>) -> Irc {
         Irc {
             writer: writer,
-            status: Mutex::new(ConnectionStatus::Connected),
+            status: Mutex::new(ConnectionStatus::Connecting),
+            status_changed: Condvar::new(),
+            history: Mutex::new(StatusHistory {
+                connected_since: Some(Instant::now()),
+                ..StatusHistory::default()
+            }),
             channels: Mutex::new(HashMap::new()),
+            chain_ops: Mutex::new(Vec::new()),
+            network: network.into(),
+            nickname: Mutex::new(Arc::new(nickname.into())),
+            accounts: Mutex::new(HashMap::new()),
+            isupport: Mutex::new(ISupport::default()),
+            server_info: Mutex::new(None),
+            motd: Mutex::new(None),
+            list: Mutex::new(None),
+            keys: Mutex::new(HashMap::new()),
+            pending_joins: Mutex::new(HashSet::new()),
+            away_message: Mutex::new(None),
+            away: Mutex::new(false),
+            caps: Mutex::new(Vec::new()),
+            presence: Mutex::new(Presence::default()),
+            disconnect_reason: Mutex::new(None),
+            lag: Mutex::new(lag.map(LagMeter::new)),
+            queue: Mutex::new(Vec::new()),
+            connection: Mutex::new(0),
         }
     }
 
+    fn take_chain_ops(&self) -> Vec<ChainOp> {
+        mem::take(&mut *self.chain_ops.lock().unwrap())
+    }
+
     fn get_channel_by_id(&self, id: &str) -> Option<Arc<Channel>> {
         self.channels.lock().unwrap().get(id).map(|c| c.clone())
     }
 
     fn ensure_channel_exists(&self, name: &str, id: &str) {
-        self.channels.lock().unwrap().entry(id.into()).or_insert(Arc::new(Channel::new(name)));
+        let casemapping = self.casemapping();
+        self.channels.lock().unwrap().entry(id.into()).or_insert_with(|| Arc::new(Channel::new(name, casemapping)));
     }
 
     fn channel_set_topic(&self, channel_id: &str, topic: &str) {
@@ -390,11 +1202,10 @@ impl Irc {
         channel.add_user(Arc::new(ChannelUser::from_raw(raw)));
     }
 
-    fn channel_del_user(&self, channel_id: &str, nickname: &str) -> Option<Arc<ChannelUser>> {
-        if let Some(channel) = self.get_channel_by_id(channel_id) {
-            channel.remove_user(nickname);
-        }
-        None
+    fn channel_add_pending_name(&self, channel_id: &str, raw: &str) {
+        let channels = self.channels.lock().unwrap();
+        let channel = some_or_return!(channels.get(channel_id));
+        channel.add_pending_name(ChannelUser::from_raw(raw));
     }
 
     fn channel_update_user_mode(&self, channel_id: &str, nickname: &str, mode: &str) -> Option<(ChannelUserStatus, ChannelUserStatus)> {
@@ -437,12 +1248,318 @@ impl Irc {
         None
     }
 
-    fn clear_channels(&self) {
-        self.channels.lock().unwrap().clear();
+    // Remember the channels of a JOIN, to tell if it failed and to rejoin with the same keys.
+    pub(crate) fn joining(&self, channels: &str, keys: Option<&str>) {
+        let mut keys = keys.map(|k| k.split(',')).into_iter().flatten();
+        let mut pending = self.pending_joins.lock().unwrap();
+        for channel in channels.split(',') {
+            pending.insert(self.normalize(channel));
+            if let Some(key) = keys.next() {
+                self.remember_key(channel, Some(key));
+            }
+        }
+    }
+
+    /// Check if we are waiting for the server to answer a JOIN for this channel.
+    pub fn is_joining(&self, channel: &str) -> bool {
+        self.pending_joins.lock().unwrap().contains(&self.normalize(channel))
+    }
+
+    fn end_join(&self, channel: &str) -> bool {
+        self.pending_joins.lock().unwrap().remove(&self.normalize(channel))
+    }
+
+    fn clear_pending_joins(&self) {
+        self.pending_joins.lock().unwrap().clear();
+    }
+
+    fn remove_channel(&self, channel_id: &str) {
+        self.channels.lock().unwrap().remove(channel_id);
+    }
+
+    fn key(&self, channel: &str) -> Option<String> {
+        self.keys.lock().unwrap().get(&self.normalize(channel)).cloned()
+    }
+
+    fn remember_key(&self, channel: &str, key: Option<&str>) {
+        let mut keys = self.keys.lock().unwrap();
+        match key {
+            Some(key) => keys.insert(self.normalize(channel), key.into()),
+            None => keys.remove(&self.normalize(channel)),
+        };
+    }
+
+    fn clear_channels(&self) {
+        self.channels.lock().unwrap().clear();
+    }
+
+    fn desync_channels(&self) {
+        for channel in self.channels.lock().unwrap().values() {
+            channel.desync();
+        }
+    }
+
+    fn set_account(&self, nickname: &str, account: Option<&str>) {
+        let mut accounts = self.accounts.lock().unwrap();
+        match account {
+            Some(account) => accounts.insert(self.normalize(nickname), account.into()),
+            None => accounts.remove(&self.normalize(nickname)),
+        };
+    }
+
+    fn rename_account(&self, oldnick: &str, newnick: &str) {
+        let mut accounts = self.accounts.lock().unwrap();
+        if let Some(account) = accounts.remove(&self.normalize(oldnick)) {
+            accounts.insert(self.normalize(newnick), account);
+        }
+    }
+
+    fn clear_accounts(&self) {
+        self.accounts.lock().unwrap().clear();
+    }
+
+    fn set_server_info(&self, info: ServerInfo) {
+        *self.server_info.lock().unwrap() = Some(info);
+    }
+
+    pub(crate) fn going_away(&self, message: Option<&str>) {
+        *self.away_message.lock().unwrap() = message.filter(|m| !m.is_empty()).map(|m| Arc::new(m.into()));
+    }
+
+    fn set_away(&self, away: bool) {
+        *self.away.lock().unwrap() = away;
+    }
+
+    pub(crate) fn watch_with<W: IrcWrite>(&self, w: &W, nicknames: &[&str]) -> Result<(), Error> {
+        for nickname in nicknames {
+            check_target(nickname)?;
+        }
+        let casemapping = self.casemapping();
+        let (added, method) = {
+            let mut presence = self.presence.lock().unwrap();
+            (presence.watch(nicknames, casemapping), presence.method())
+        };
+        match method {
+            Some(Method::Monitor) => send_monitor(w, "+", &added),
+            Some(Method::Ison) => send_ison(w, &added),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn unwatch_with<W: IrcWrite>(&self, w: &W, nicknames: &[&str]) -> Result<(), Error> {
+        let casemapping = self.casemapping();
+        let (removed, method) = {
+            let mut presence = self.presence.lock().unwrap();
+            (presence.unwatch(nicknames, casemapping), presence.method())
+        };
+        match method {
+            Some(Method::Monitor) => send_monitor(w, "-", &removed),
+            _ => Ok(()),
+        }
+    }
+
+    // Send the whole watch list once registered, returns the generation of the ISON polling.
+    fn start_presence(&self, method: Method) -> u64 {
+        let (generation, watched) = {
+            let mut presence = self.presence.lock().unwrap();
+            (presence.start(method), presence.watched().to_vec())
+        };
+        let _ = match method {
+            Method::Monitor => send_monitor(self, "+", &watched),
+            Method::Ison => send_ison(self, &watched),
+        };
+        generation
+    }
+
+    // Ask for the presence of the watched nicknames, returns false once the polling must stop.
+    fn poll_ison(&self, generation: u64) -> bool {
+        let targets = {
+            let presence = self.presence.lock().unwrap();
+            if presence.generation() != generation {
+                return false;
+            }
+            presence.ison_targets()
+        };
+        let _ = send_ison(self, &targets);
+        true
+    }
+
+    // The monitor list is full, ask for the refused nicknames with ISON instead.
+    fn monitor_full(&self, nicknames: &[String]) {
+        let refused = self.presence.lock().unwrap().monitor_refused(nicknames, self.casemapping());
+        let _ = send_ison(self, &refused);
+    }
+
+    fn stop_presence(&self) {
+        self.presence.lock().unwrap().stop();
+    }
+
+    fn set_online(&self, nickname: &str, online: bool) -> bool {
+        self.presence.lock().unwrap().set_online(nickname, online, self.casemapping())
+    }
+
+    fn ison_reply(&self, online: &[String]) -> Vec<(String, bool)> {
+        self.presence.lock().unwrap().ison_reply(online, self.casemapping())
+    }
+
+    // Start a new connection, returns its number.
+    fn next_connection(&self) -> u64 {
+        let mut connection = self.connection.lock().unwrap();
+        *connection += 1;
+        *connection
+    }
+
+    // Drop the connection if it's still not registered, to trigger a reconnection.
+    fn check_registration(&self, connection: u64) {
+        if *self.connection.lock().unwrap() == connection && self.status().is_registering() {
+            let _ = self.writer.disconnect();
+        }
+    }
+
+    fn ack_caps(&self, caps: &str) {
+        let mut enabled = self.caps.lock().unwrap();
+        for cap in caps.split_whitespace() {
+            match cap.strip_prefix('-') {
+                Some(cap) => enabled.retain(|c| !c.eq_ignore_ascii_case(cap)),
+                None if !enabled.iter().any(|c| c.eq_ignore_ascii_case(cap)) => enabled.push(cap.into()),
+                None => {}
+            }
+        }
+    }
+
+    fn clear_caps(&self) {
+        self.caps.lock().unwrap().clear();
+    }
+
+    // Complete the registration and send the commands queued in the meantime.
+    //
+    // The status stays locked while sending, so that the queued commands are sent first.
+    fn set_registered(&self) {
+        let mut status = self.status.lock().unwrap();
+        self.change_status(&mut status, ConnectionStatus::Registered);
+        for raw in mem::take(&mut *self.queue.lock().unwrap()) {
+            let _ = self.write(&raw);
+        }
+    }
+
+    // Write a line on the socket.
+    //
+    // Replies to ISON don't say which nicknames were asked, so the nicknames of every ISON
+    // are recorded as it's written, whether it's ours or the user's. Replies come in order.
+    fn write(&self, raw: &str) -> Result<(), Error> {
+        if let Some(nicknames) = ison_nicknames(raw) {
+            self.presence.lock().unwrap().ison_sent(nicknames);
+        }
+        self.writer.raw(format!("{}\n", raw))?;
+        Ok(())
+    }
+
+    // Start measuring the lag, returns the generation of the PINGs and their interval.
+    fn start_lag(&self) -> Option<(u64, Duration)> {
+        let mut lag = self.lag.lock().unwrap();
+        let meter = lag.as_mut()?;
+        Some((meter.start(), meter.interval()))
+    }
+
+    // Send a PING to measure the lag, returns false once the PINGs must stop.
+    //
+    // The connection is dropped if it's stalled, to trigger a reconnection.
+    fn ping_lag(&self, generation: u64) -> bool {
+        let mut lag = self.lag.lock().unwrap();
+        let meter = match lag.as_mut() {
+            Some(meter) if meter.generation() == generation => meter,
+            _ => return false,
+        };
+        let now = Instant::now();
+        if meter.is_stalled(now) {
+            meter.stop();
+            let _ = self.writer.disconnect();
+            return false;
+        }
+        if let Some(token) = meter.ping(now) {
+            let _ = self.ping(&token);
+        }
+        true
+    }
+
+    fn pong_lag(&self, token: &str) -> Option<Duration> {
+        self.lag.lock().unwrap().as_mut().and_then(|meter| meter.pong(token, Instant::now()))
+    }
+
+    fn stop_lag(&self) {
+        if let Some(meter) = self.lag.lock().unwrap().as_mut() {
+            meter.stop();
+        }
+    }
+
+    fn set_disconnect_reason(&self, reason: DisconnectReason) {
+        *self.disconnect_reason.lock().unwrap() = Some(reason);
+    }
+
+    fn set_motd(&self, motd: Option<String>) {
+        *self.motd.lock().unwrap() = motd.map(Arc::new);
+    }
+
+    // Parameter of the LIST command for the given filter.
+    pub(crate) fn list_param(&self, filter: Option<&ListFilter>) -> Option<String> {
+        filter.cloned().unwrap_or_default().param(Some(self.isupport().get("ELIST").unwrap_or("")))
+    }
+
+    // Begin a new channel list, once the LIST command was sent.
+    pub(crate) fn start_list(&self, filter: Option<&ListFilter>) {
+        *self.list.lock().unwrap() = Some(PendingList::new(filter.cloned().unwrap_or_default()));
+    }
+
+    fn push_list_entry(&self, entry: &ListEntry) -> bool {
+        let mut list = self.list.lock().unwrap();
+        list.get_or_insert_with(|| PendingList::new(ListFilter::default())).push(entry)
+    }
+
+    fn expire_list(&self) -> Option<ChannelList> {
+        match *self.list.lock().unwrap() {
+            Some(ref mut list) if list.is_over_limit() => list.finish(false),
+            _ => None,
+        }
+    }
+
+    fn end_list(&self, complete: bool) -> Option<ChannelList> {
+        self.list.lock().unwrap().take().and_then(|mut list| list.finish(complete))
+    }
+
+    fn update_isupport(&self, tokens: &[String]) {
+        self.isupport.lock().unwrap().update(tokens);
+    }
+
+    fn set_nickname(&self, nickname: &str) {
+        *self.nickname.lock().unwrap() = Arc::new(nickname.into());
     }
 
     fn set_status(&self, status: ConnectionStatus) {
-        *self.status.lock().unwrap() = status;
+        self.change_status(&mut self.status.lock().unwrap(), status);
+    }
+
+    // Change the status while its lock is held, and record the transition.
+    fn change_status(&self, status: &mut ConnectionStatus, next: ConnectionStatus) {
+        let now = Instant::now();
+        let mut history = self.history.lock().unwrap();
+        match next {
+            ConnectionStatus::Connecting => {
+                if let ConnectionStatus::Reconnecting = *status {
+                    history.reconnections += 1;
+                }
+                history.connected_since = Some(now);
+                history.disconnected_since = None;
+            }
+            ConnectionStatus::Registered => history.reconnect_attempts = 0,
+            ConnectionStatus::Reconnecting => history.reconnect_attempts += 1,
+            ConnectionStatus::Disconnected | ConnectionStatus::Closed(_) => {
+                history.connected_since = None;
+                history.disconnected_since.get_or_insert(now);
+            }
+            _ => {}
+        }
+        *status = next;
+        self.status_changed.notify_all();
     }
 
 }
@@ -454,29 +1571,75 @@ impl IrcWrite for Irc {
         if raw.contains("\n") || raw.contains("\r") {
             return Err(Error::Multiline)
         }
-        try!(self.writer.raw(format!("{}\n", raw)));
+        {
+            let mut status = self.status.lock().unwrap();
+            if status.is_registering() {
+                let (command, params) = raw.split_once(' ').unwrap_or((raw, ""));
+                let command = command.to_ascii_uppercase();
+                if !REGISTRATION_COMMANDS.contains(&&command[..]) {
+                    self.queue.lock().unwrap().push(raw.into());
+                    return Ok(());
+                }
+                if let Some(next) = status.after_sending(&command, params.trim_start_matches(':')) {
+                    self.change_status(&mut status, next);
+                }
+            }
+        }
+        self.write(raw)
+    }
+
+    fn isupport(&self) -> ISupport {
+        self.isupport.lock().unwrap().clone()
+    }
+
+    // The state is only recorded once the command is known to be valid.
+    fn join(&self, channel: &str, password: Option<&str>) -> Result<(), Error> {
+        check_target(channel)?;
+        let line = match password {
+            None => serialize("JOIN", &[channel], None)?,
+            Some(password) => serialize("JOIN", &[channel, password], None)?,
+        };
+        self.joining(channel, password);
+        self.raw(line)
+    }
+
+    fn away(&self, message: Option<&str>) -> Result<(), Error> {
+        let line = serialize("AWAY", &[], message)?;
+        self.going_away(message);
+        self.raw(line)
+    }
+
+    fn list(&self, filter: Option<&ListFilter>) -> Result<(), Error> {
+        match self.list_param(filter) {
+            None => self.command("LIST", &[], None)?,
+            Some(param) => self.command("LIST", &[&param], None)?,
+        }
+        self.start_list(filter);
         Ok(())
     }
 
 }
 
-/// Create an irc client with the listener and settings.
-pub fn dispatch<L: Listener>(listener: L, settings: Settings) -> Result<(), Error> {
-    let (writer, reader) = try!(connect(settings.addr, settings.reconnection, settings.encoding));
-
-    let irc = Irc::new(writer.clone());
+// Send the registration messages, starting the capability negotiation if it's needed.
+fn register(irc: &Irc, settings: &Settings) -> Result<(), Error> {
+    if !settings.caps.is_empty() || settings.sasl.is_some() {
+        irc.command("CAP", &["LS", "302"], None)?;
+    }
     if !settings.password.is_empty() {
-        try!(irc.pass(settings.password));
+        irc.pass(settings.password)?;
     }
-    try!(irc.nick(settings.nickname));
-    try!(irc.user(settings.username, settings.realname));
+    irc.nick(settings.nickname)?;
+    irc.user(settings.username, settings.realname)
+}
 
-    let mut dispatch = Dispatch {
-        am: settings.monitor.map(|s| ActivityMonitor::new(&writer, s)),
-        listener: Box::new(listener),
-        irc: Arc::new(irc),
-        settings: settings,
-    };
+/// Create an irc client with the listener and settings.
+pub fn dispatch<L: Listener>(listener: L, settings: Settings) -> Result<(), Error> {
+    dispatch_chain(Chain::new().listener(listener), settings)
+}
+
+/// Create an irc client with a chain of listeners and middlewares, and the settings.
+pub fn dispatch_chain(chain: Chain, settings: Settings) -> Result<(), Error> {
+    let (mut dispatch, reader) = Dispatch::connect(chain, settings)?;
 
     for event in reader.iter() {
         dispatch.feed(&event);
@@ -485,69 +1648,127 @@ pub fn dispatch<L: Listener>(listener: L, settings: Settings) -> Result<(), Erro
     Ok(())
 }
 
-struct Dispatch<'a> {
+pub(crate) struct Dispatch<'a> {
     am: Option<ActivityMonitor>,
-    listener: Box<Listener + 'a>,
+    chain: Chain<'a>,
     irc: Arc<Irc>,
     settings: Settings<'a>,
+    motd: Vec<String>,
+    registered: bool,
+    rejoin: Vec<String>,
+    // Token of the PING sent after rejoining the channels kept from before a reconnection.
+    rejoin_fence: Option<String>,
+    rejoiner: Option<Rejoiner>,
+    // Reason of the ERROR message received before the connection is dropped.
+    error: Option<DisconnectReason>,
+    negotiation: Negotiation,
 }
 
 impl<'a> Dispatch<'a> {
 
+    /// Connect to the server and send the registration messages.
+    ///
+    /// The events of the connection must be fed to the dispatcher using the returned `Reader`.
+    pub fn connect(chain: Chain<'a>, settings: Settings<'a>) -> Result<(Dispatch<'a>, Reader), Error> {
+        let (writer, reader) = try!(connect(settings.addr, settings.reconnection, settings.encoding));
+
+        let irc = Irc::new(writer.clone(), settings.network, settings.nickname, settings.lag);
+        register(&irc, &settings)?;
+        let connection = irc.next_connection();
+
+        let dispatch = Dispatch {
+            am: settings.monitor.map(|s| ActivityMonitor::new(&writer, s)),
+            chain,
+            irc: Arc::new(irc),
+            rejoiner: settings.kick_rejoin.map(Rejoiner::new),
+            settings,
+            motd: Vec::new(),
+            registered: false,
+            rejoin: Vec::new(),
+            rejoin_fence: None,
+            error: None,
+            negotiation: Negotiation::default(),
+        };
+
+        dispatch.registration_timeout(connection);
+        Ok((dispatch, reader))
+    }
+
+    /// Get the `Irc` object of this connection.
+    pub fn irc(&self) -> Arc<Irc> {
+        self.irc.clone()
+    }
+
     /// Feed an event to the dispatcher.
-    pub fn feed(&mut self, event: &Event) {
+    pub fn feed(&mut self, event: &loirc::Event) {
         if let Some(am) = self.am.as_ref() {
             am.feed(event);
         }
 
-        self.listener.any(self.irc.clone(), event);
+        self.emit(Event::Any(clone_event(event)));
+
+        if let Some(list) = self.irc.expire_list() {
+            self.emit(Event::ListEnd(list));
+        }
 
         match *event {
-            Event::Closed(reason) => {
-                self.irc.set_status(ConnectionStatus::Closed(reason));
-                self.listener.close(self.irc.clone(), reason);
+            loirc::Event::Closed(reason) => {
+                self.irc.set_status(ConnectionStatus::Closed(reason.into()));
+                self.irc.queue.lock().unwrap().clear();
+                self.emit(Event::Close(reason.into()));
             }
-            Event::Disconnected => {
+            loirc::Event::Disconnected => {
                 self.irc.set_status(ConnectionStatus::Disconnected);
-                self.irc.clear_channels();
-                self.listener.disconnect(self.irc.clone());
+                // The queued commands were meant for the lost connection.
+                self.irc.queue.lock().unwrap().clear();
+                if self.settings.rejoin {
+                    self.rejoin = self.irc.channels().iter().map(|c| c.name().to_string()).collect();
+                }
+                self.registered = false;
+                self.rejoin_fence = None;
+                if self.settings.rejoin {
+                    self.irc.desync_channels();
+                } else {
+                    self.irc.clear_channels();
+                }
+                self.irc.clear_accounts();
+                self.irc.clear_caps();
+                self.irc.clear_pending_joins();
+                self.irc.set_away(false);
+                self.irc.stop_presence();
+                self.irc.stop_lag();
+                if let Some(list) = self.irc.end_list(false) {
+                    self.emit(Event::ListEnd(list));
+                }
+                let error = self.error.take();
+                self.emit(Event::Disconnect(error));
             }
-            Event::Reconnecting => {
+            loirc::Event::Reconnecting => {
                 self.irc.set_status(ConnectionStatus::Reconnecting);
-                self.listener.reconnecting(self.irc.clone());
+                self.emit(Event::Reconnecting);
             }
-            Event::Reconnected => {
-                self.irc.set_status(ConnectionStatus::Connected);
+            loirc::Event::Reconnected => {
+                self.irc.set_status(ConnectionStatus::Connecting);
+                let connection = self.irc.next_connection();
                 if self.settings.auto_ident {
-                    let _ = self.irc.user(self.settings.username, self.settings.realname);
-                    let _ = self.irc.nick(self.settings.nickname);
+                    let _ = register(&self.irc, &self.settings);
                 }
-                self.listener.reconnect(self.irc.clone());
+                self.registration_timeout(connection);
+                self.emit(Event::Reconnect);
             }
-            Event::Message(ref msg) => {
-                self.listener.msg(self.irc.clone(), &msg);
+            loirc::Event::Message(ref msg) => {
+                self.emit(Event::Msg(msg.clone()));
                 if msg.code.is_error() {
-                    self.listener.error_msg(self.irc.clone(), &msg.code, &msg);
+                    self.emit(Event::ErrorMsg { code: msg.code.clone(), err: msg.clone() });
+                }
+                if let Some(reply) = Reply::parse(msg) {
+                    self.emit(Event::Reply(reply.clone()));
+                    self.reply(&reply);
                 }
                 match msg.code {
-                    Code::RplWelcome => {
-                        self.listener.welcome(self.irc.clone());
-                    }
-                    Code::RplNamreply => {
-                        self.name_reply(msg);
-                    }
-                    Code::RplEndofnames => {
-                        self.end_name_reply(msg);
-                    }
                     Code::Topic => {
                         self.topic(msg);
                     }
-                    Code::RplTopic => {
-                        self.rpl_topic(msg);
-                    }
-                    Code::RplNotopic => {
-                        self.rpl_no_topic(msg);
-                    }
                     Code::Join => {
                         self.join(msg);
                     }
@@ -578,87 +1799,234 @@ impl<'a> Dispatch<'a> {
                     Code::Mode => {
                         self.mode(msg);
                     }
+                    Code::Invite => {
+                        self.invite(msg);
+                    }
+                    Code::Error => {
+                        self.error(msg);
+                    }
+                    Code::Unknown(ref code) if code == "ACCOUNT" => {
+                        self.account(msg);
+                    }
+                    Code::Unknown(ref code) if code == "CAP" => {
+                        self.cap(msg);
+                    }
+                    Code::Unknown(ref code) if code == "AUTHENTICATE" => {
+                        self.authenticate(msg);
+                    }
+                    // RPL_SASLSUCCESS, ERR_SASLFAIL, ERR_SASLTOOLONG, ERR_SASLABORTED, ERR_SASLALREADY
+                    Code::Unknown(ref code) if ["903", "904", "905", "906", "907"].contains(&&code[..])
+                        && self.irc.status() == ConnectionStatus::Authenticating => {
+                        let _ = self.irc.command("CAP", &["END"], None);
+                    }
                     _ => {}
                 }
             }
             _ => {}
         }
+
+        let ops = self.irc.take_chain_ops();
+        self.chain.apply(ops);
+    }
+
+    fn emit(&mut self, mut event: Event) {
+        self.chain.emit(&self.irc, &mut event);
+    }
+
+    fn reply(&mut self, reply: &Reply) {
+        match *reply {
+            Reply::Welcome { ref nickname, .. } => self.welcome(nickname),
+            Reply::ISupport { ref tokens } => self.irc.update_isupport(tokens),
+            Reply::Names { ref channel, ref names, .. } => self.name_reply(channel, names),
+            Reply::EndOfNames { ref channel } => self.end_name_reply(channel),
+            Reply::Topic { ref channel, ref topic } => self.rpl_topic(channel, topic),
+            Reply::NoTopic { ref channel } => self.rpl_no_topic(channel),
+            Reply::WhoisAccount { ref nickname, ref account } => self.irc.set_account(nickname, Some(account)),
+            Reply::BanList { ref channel, ref mask, ref set_by, set_at } => {
+                self.ban_list(channel, mask, set_by.clone(), set_at)
+            }
+            Reply::EndOfBanList { ref channel } => self.end_ban_list(channel),
+            Reply::InviteList { ref channel, ref mask, ref set_by, set_at } => {
+                self.invite_list(channel, mask, set_by.clone(), set_at)
+            }
+            Reply::EndOfInviteList { ref channel } => self.end_invite_list(channel),
+            Reply::MyInfo { ref server, ref version, ref user_modes, ref channel_modes } => {
+                self.irc.set_server_info(ServerInfo {
+                    name: server.clone(),
+                    version: version.clone(),
+                    user_modes: user_modes.clone(),
+                    channel_modes: channel_modes.clone(),
+                });
+            }
+            Reply::List { ref channel, users, ref topic } => self.list_entry(channel, users, topic),
+            Reply::ListEnd => {
+                if let Some(list) = self.irc.end_list(true) {
+                    self.emit(Event::ListEnd(list));
+                }
+            }
+            Reply::Away { ref nickname, ref message } => {
+                self.emit(Event::UserAway { nickname: nickname.clone(), message: message.clone() });
+            }
+            Reply::IsOn { ref nicknames } => {
+                for (nickname, online) in self.irc.ison_reply(nicknames) {
+                    self.emit(presence_event(nickname, online));
+                }
+            }
+            Reply::MonListFull { ref nicknames, .. } => self.irc.monitor_full(nicknames),
+            Reply::MonOnline { ref targets } => {
+                for target in targets {
+                    let nickname = target.split('!').next().unwrap_or(target);
+                    self.presence(nickname, true);
+                }
+            }
+            Reply::MonOffline { ref nicknames } => {
+                for nickname in nicknames {
+                    self.presence(nickname, false);
+                }
+            }
+            Reply::Unaway => self.irc.set_away(false),
+            Reply::NowAway => self.irc.set_away(true),
+            Reply::MotdStart { .. } => self.motd.clear(),
+            Reply::Motd { ref line } => self.motd.push(line.clone()),
+            Reply::EndOfMotd => self.end_motd(true),
+            Reply::Error { code: Code::ErrNomotd, .. } => self.end_motd(false),
+            Reply::Error { ref code, ref params, ref message } => {
+                if let Some(channel_name) = params.first() {
+                    self.join_failed(channel_name, code, message);
+                }
+            }
+            _ => {}
+        }
     }
 
-    fn name_reply(&mut self, msg: &Message) {
-        let channel_name = some_or_return!(msg.args.get(2));
-        let channel_id = channel_name.to_lowercase();
-        let user_list = some_or_return!(msg.args.last());
+    fn name_reply(&mut self, channel_name: &str, names: &[String]) {
+        let channel_id = self.irc.normalize(channel_name);
 
         self.irc.ensure_channel_exists(channel_name, &channel_id);
-        for raw in user_list.split(" ") {
-            self.irc.channel_add_user(&channel_id, raw);
+        for raw in names {
+            self.irc.channel_add_pending_name(&channel_id, raw);
         }
     }
 
-    fn end_name_reply(&mut self, msg: &Message) {
-        let channel_name = some_or_return!(msg.args.get(1));
-        let channel = some_or_return!(self.irc.channel(&channel_name));
-        self.listener.channel_join(self.irc.clone(), channel);
+    fn end_name_reply(&mut self, channel_name: &str) {
+        let channel = some_or_return!(self.irc.channel(channel_name));
+        let desynced = channel.is_desynced();
+        let (joined, left) = channel.finish_names();
+
+        let me = channel.user(&self.irc.nickname());
+        if me.map(|u| u.status() >= ChannelUserStatus::Operator).unwrap_or(false) {
+            self.request_invites(channel_name);
+        }
+        self.emit(Event::ChannelJoin(channel.clone()));
+        if desynced {
+            let joined = joined.into_iter().filter(|u| !self.irc.is_me(&u.nickname())).collect();
+            self.emit(Event::ChannelResync { channel, joined, left });
+        }
     }
 
     fn topic(&mut self, msg: &Message) {
         let topic = some_or_return!(msg.args.last());
         let channel_name = some_or_return!(msg.args.get(0));
-        let channel_id = channel_name.to_lowercase();
+        let channel_id = self.irc.normalize(channel_name);
 
-        self.irc.ensure_channel_exists(&channel_id, channel_name);
+        self.irc.ensure_channel_exists(channel_name, &channel_id);
         self.irc.channel_set_topic(&channel_id, topic);
 
         let channel = some_or_return!(self.irc.get_channel_by_id(&channel_id));
-        self.listener.topic_change(self.irc.clone(), channel.clone(), channel.topic());
+        let topic = channel.topic();
+        self.emit(Event::TopicChange { channel, topic });
     }
 
-    fn rpl_topic(&mut self, msg: &Message) {
-        let topic = some_or_return!(msg.args.last());
-        let channel_name = some_or_return!(msg.args.get(1));
-        let channel_id = channel_name.to_lowercase();
+    fn rpl_topic(&mut self, channel_name: &str, topic: &str) {
+        let channel_id = self.irc.normalize(channel_name);
 
-        self.irc.ensure_channel_exists(&channel_id, channel_name);
+        self.irc.ensure_channel_exists(channel_name, &channel_id);
         self.irc.channel_set_topic(&channel_id, topic);
 
         let channel = some_or_return!(self.irc.get_channel_by_id(&channel_id));
-        self.listener.topic(self.irc.clone(), channel.clone(), channel.topic());
+        let topic = channel.topic();
+        self.emit(Event::Topic { channel, topic });
     }
 
-    fn rpl_no_topic(&mut self, msg: &Message) {
-        let channel_name = some_or_return!(msg.args.get(0));
-        let channel_id = channel_name.to_lowercase();
+    fn rpl_no_topic(&mut self, channel_name: &str) {
+        let channel_id = self.irc.normalize(channel_name);
 
         self.irc.ensure_channel_exists(channel_name, &channel_id);
         self.irc.channel_set_topic(&channel_id, "");
 
         let channel = some_or_return!(self.irc.get_channel_by_id(&channel_id));
-        self.listener.topic(self.irc.clone(), channel, None);
+        self.emit(Event::Topic { channel, topic: None });
     }
 
     fn join(&mut self, msg: &Message) {
         let prefix = user_or_return!(msg.prefix);
         let channel_name = some_or_return!(msg.args.get(0));
-        let channel_id = channel_name.to_lowercase();
+        let channel_id = self.irc.normalize(channel_name);
 
-        self.irc.channel_add_user(&channel_id, &prefix.nickname);
+        // The users of a channel kept from before a reconnection are updated by the NAMES
+        // reply which follows our JOIN.
+        let desynced = self.irc.get_channel_by_id(&channel_id).map(|c| c.is_desynced()).unwrap_or(false);
+        if !desynced {
+            self.irc.channel_add_user(&channel_id, &prefix.nickname);
+        }
+        if self.irc.is_me(&prefix.nickname) {
+            self.irc.end_join(channel_name);
+            if let Some(rejoiner) = self.rejoiner.as_mut() {
+                rejoiner.joined(&channel_id);
+            }
+        }
+        if desynced {
+            return;
+        }
 
         let channel = some_or_return!(self.irc.get_channel_by_id(&channel_id));
         let user = some_or_return!(channel.user(&prefix.nickname));
-        self.listener.user_join(self.irc.clone(), channel, user);
+        self.emit(Event::UserJoin { channel, user });
+    }
+
+    fn join_failed(&mut self, channel_name: &str, code: &Code, message: &str) {
+        let reason = some_or_return!(JoinFailure::from_code(code));
+        let joining = self.irc.end_join(channel_name);
+        if joining {
+            self.emit(Event::JoinFailed { channel: channel_name.into(), reason, message: message.into() });
+        }
+
+        let channel_id = self.irc.normalize(channel_name);
+        match self.rejoiner.as_mut().and_then(|r| r.failed(&channel_id, code)) {
+            Some(delay) => self.schedule_join(channel_name, delay),
+            None => {
+                // We could not get back in the channel kept from before the reconnection.
+                let desynced = self.irc.get_channel_by_id(&channel_id).map(|c| c.is_desynced()).unwrap_or(false);
+                if joining && desynced {
+                    self.irc.remove_channel(&channel_id);
+                }
+            }
+        }
+    }
+
+    fn schedule_join(&self, channel_name: &str, delay: Duration) {
+        let irc = self.irc.clone();
+        let channel = channel_name.to_string();
+        let key = self.irc.key(channel_name);
+        thread::spawn(move || {
+            thread::sleep(delay);
+            if !irc.is_closed() {
+                let _ = irc.join(&channel, key.as_deref());
+            }
+        });
     }
 
     fn part(&mut self, msg: &Message) {
         let prefix = user_or_return!(msg.prefix);
         let channel_name = some_or_return!(msg.args.get(0));
-        let channel_id = channel_name.to_lowercase();
-
-        self.irc.channel_del_user(&channel_id, &prefix.nickname);
+        let channel_id = self.irc.normalize(channel_name);
 
         let channel = some_or_return!(self.irc.get_channel_by_id(&channel_id));
-        let user = some_or_return!(channel.user(&prefix.nickname));
-        self.listener.user_part(self.irc.clone(), channel, user);
+        let user = some_or_return!(channel.remove_user(&prefix.nickname));
+        if self.irc.is_me(&prefix.nickname) {
+            self.irc.remove_channel(&channel_id);
+        }
+        self.emit(Event::UserPart { channel, user });
     }
 
     fn message(&mut self, msg: &Message, notice: bool) {
@@ -666,19 +2034,19 @@ impl<'a> Dispatch<'a> {
         let text = some_or_return!(msg.args.last());
         let source = some_or_return!(msg.args.get(0));
 
-        if source.starts_with("#") {
+        if self.irc.is_channel_name(source) {
             let channel = some_or_return!(self.irc.channel(&source));
             let user = some_or_return!(channel.user(&prefix.nickname));
             if !notice {
-                self.listener.channel_msg(self.irc.clone(), channel, user, text);
+                self.emit(Event::ChannelMsg { channel, sender: user, message: text.clone() });
             } else {
-                self.listener.channel_notice(self.irc.clone(), channel, user, text);
+                self.emit(Event::ChannelNotice { channel, sender: user, message: text.clone() });
             }
         } else {
             if !notice {
-                self.listener.private_msg(self.irc.clone(), prefix, text);
+                self.emit(Event::PrivateMsg { sender: prefix.clone(), message: text.clone() });
             } else {
-                self.listener.private_notice(self.irc.clone(), prefix, text);
+                self.emit(Event::PrivateNotice { sender: prefix.clone(), message: text.clone() });
             }
         }
     }
@@ -689,31 +2057,117 @@ impl<'a> Dispatch<'a> {
         for channel in self.irc.channels() {
             channel.remove_user(&user.nickname);
         }
+        self.irc.set_account(&user.nickname, None);
+
+        self.emit(Event::UserQuit(user.nickname.clone()));
+    }
 
-        self.listener.user_quit(self.irc.clone(), &user.nickname);
+    fn account(&mut self, msg: &Message) {
+        let user = user_or_return!(msg.prefix);
+        let account = some_or_return!(msg.args.first());
+        if account == "*" {
+            self.irc.set_account(&user.nickname, None);
+        } else {
+            self.irc.set_account(&user.nickname, Some(account));
+        }
+    }
+
+    fn cap(&mut self, msg: &Message) {
+        let subcommand = some_or_return!(msg.args.get(1)).to_ascii_uppercase();
+        let caps = some_or_return!(msg.args.last());
+        match &subcommand[..] {
+            "LS" => {
+                // Every line but the last has a `*` before the capabilities.
+                let last = msg.args.len() < 4 || msg.args[2] != "*";
+                let mut wanted = self.settings.caps.clone();
+                if self.settings.sasl.is_some() {
+                    wanted.push("sasl");
+                }
+                match self.negotiation.ls(caps, last, &wanted) {
+                    Some(ref request) if request.is_empty() => {
+                        let _ = self.irc.command("CAP", &["END"], None);
+                    }
+                    Some(request) => {
+                        let _ = self.irc.command("CAP", &["REQ"], Some(&request.join(" ")));
+                    }
+                    None => {}
+                }
+            }
+            "ACK" => {
+                self.irc.ack_caps(caps);
+                if !self.irc.status().is_registering() {
+                    return;
+                }
+                let sasl = caps.split_whitespace().any(|c| c.eq_ignore_ascii_case("sasl"));
+                if sasl && self.settings.sasl.is_some() {
+                    let _ = self.irc.command("AUTHENTICATE", &["PLAIN"], None);
+                } else {
+                    let _ = self.irc.command("CAP", &["END"], None);
+                }
+            }
+            "NAK" if self.irc.status().is_registering() => {
+                let _ = self.irc.command("CAP", &["END"], None);
+            }
+            _ => {}
+        }
+    }
+
+    fn authenticate(&mut self, msg: &Message) {
+        if msg.args.first().map(|a| a != "+").unwrap_or(true) {
+            return;
+        }
+        let (account, password) = some_or_return!(self.settings.sasl);
+        for payload in caps::plain_payloads(account, password) {
+            let _ = self.irc.command("AUTHENTICATE", &[&payload], None);
+        }
+    }
+
+    fn welcome(&mut self, nickname: &str) {
+        self.irc.set_nickname(nickname);
+        self.irc.set_registered();
+        self.emit(Event::Welcome);
     }
 
     fn nick(&mut self, msg: &Message) {
         let prefix = user_or_return!(msg.prefix);
         let newname = some_or_return!(msg.args.last());
 
+        if self.irc.is_me(&prefix.nickname) {
+            self.irc.set_nickname(newname);
+        }
+        self.irc.rename_account(&prefix.nickname, newname);
+
         for channel in self.irc.channels() {
             if let Some(user) = channel.user(&prefix.nickname) {
                 user.set_nickname(newname);
             }
         }
 
-        self.listener.nick_change(self.irc.clone(), &prefix.nickname, &newname);
+        self.emit(Event::NickChange { oldnick: prefix.nickname.clone(), newnick: newname.clone() });
     }
 
     fn kick(&mut self, msg: &Message) {
-        let kicked_user = some_or_return!(msg.args.last());
-        let channel_name = some_or_return!(msg.args.get(0));
-        let channel_id = channel_name.to_lowercase();
+        let channel_name = some_or_return!(msg.args.first());
+        let kicked_user = some_or_return!(msg.args.get(1));
+        let channel_id = self.irc.normalize(channel_name);
 
-        let channel_user = some_or_return!(self.irc.channel_del_user(&channel_id, kicked_user));
         let channel = some_or_return!(self.irc.get_channel_by_id(&channel_id));
-        self.listener.kick(self.irc.clone(), channel, channel_user);
+        let channel_user = some_or_return!(channel.remove_user(kicked_user));
+        if self.irc.is_me(kicked_user) {
+            self.irc.remove_channel(&channel_id);
+            if let Some(delay) = self.rejoiner.as_mut().and_then(|r| r.kicked(&channel_id)) {
+                self.schedule_join(channel_name, delay);
+            }
+        }
+        self.emit(Event::Kick { channel, user: channel_user });
+    }
+
+    // The server sends ERROR right before closing the connection.
+    fn error(&mut self, msg: &Message) {
+        let message = some_or_return!(msg.args.last());
+        let reason = DisconnectReason::parse(message);
+        self.irc.set_disconnect_reason(reason.clone());
+        self.error = Some(reason);
     }
 
     fn ping(&mut self, msg: &Message) {
@@ -721,30 +2175,359 @@ impl<'a> Dispatch<'a> {
         if self.settings.auto_ping {
             let _ = self.irc.pong(server);
         }
-        self.listener.ping(self.irc.clone(), server);
+        self.emit(Event::Ping(server.clone()));
     }
 
     fn pong(&mut self, msg: &Message) {
         let server = some_or_return!(msg.args.last());
-        self.listener.pong(self.irc.clone(), server);
+        if self.rejoin_fence.as_ref() == Some(server) {
+            self.rejoin_fence = None;
+            self.drop_desynced();
+        }
+        if let Some(lag) = self.irc.pong_lag(server) {
+            self.emit(Event::Lag(lag));
+        }
+        self.emit(Event::Pong(server.clone()));
     }
 
     fn mode(&mut self, msg: &Message) {
-        let mode = some_or_return!(msg.args.get(1));
-        let nickname = some_or_return!(msg.args.get(2));
-        let channel_name = some_or_return!(msg.args.get(0));
-        let channel_id = channel_name.to_lowercase();
+        let channel_name = some_or_return!(msg.args.first());
+        let modes = some_or_return!(msg.args.get(1));
+        let channel_id = self.irc.normalize(channel_name);
+        let channel = some_or_return!(self.irc.get_channel_by_id(&channel_id));
+
+        let isupport = self.irc.isupport();
+        let prefix = isupport.prefix();
+        let invex = isupport.invex();
+
+        for change in isupport.parse_modes(modes, &msg.args[2..]) {
+            let param = match change.param {
+                Some(ref param) => param,
+                None => continue,
+            };
+            if change.mode == 'b' || Some(change.mode) == invex {
+                let entry = Ban {
+                    mask: Hostmask::parse(param),
+                    set_by: msg.prefix.as_ref().map(prefix_name),
+                    set_at: SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()),
+                };
+                match (change.mode == 'b', change.set) {
+                    (true, true) => channel.add_ban(entry),
+                    (true, false) => channel.remove_ban(&entry.mask),
+                    (false, true) => channel.add_invite(entry),
+                    (false, false) => channel.remove_invite(&entry.mask),
+                }
+            } else if change.mode == 'k' {
+                self.irc.remember_key(channel_name, if change.set { Some(param) } else { None });
+            } else if prefix.iter().any(|&(mode, _)| mode == change.mode) {
+                let mode = format!("{}{}", if change.set { '+' } else { '-' }, change.mode);
+                self.user_mode(&channel_id, param, &mode);
+            }
+        }
+    }
 
-        if let Some((old_status, new_status)) = self.irc.channel_update_user_mode(&channel_id, nickname, mode) {
+    fn user_mode(&mut self, channel_id: &str, nickname: &str, mode: &str) {
+        if let Some((old_status, new_status)) = self.irc.channel_update_user_mode(channel_id, nickname, mode) {
             if old_status != new_status {
-                let channel = some_or_return!(self.irc.get_channel_by_id(&channel_id));
+                let channel = some_or_return!(self.irc.get_channel_by_id(channel_id));
                 let user = some_or_return!(channel.user(nickname));
                 let status = user.status();
-                self.listener.user_mode_change(self.irc.clone(), channel, user, old_status, status);
+                if self.irc.is_me(nickname) && old_status < ChannelUserStatus::Operator && status >= ChannelUserStatus::Operator {
+                    self.request_invites(channel.name());
+                }
+                self.emit(Event::UserModeChange { channel, user, old_status, new_status: status });
+            }
+        }
+    }
+
+    fn ban_list(&mut self, channel_name: &str, mask: &str, set_by: Option<String>, set_at: Option<u64>) {
+        let channel = some_or_return!(self.irc.channel(channel_name));
+        channel.add_pending_ban(Ban {
+            mask: Hostmask::parse(mask),
+            set_by,
+            set_at,
+        });
+    }
+
+    fn list_entry(&mut self, channel: &str, users: u32, topic: &str) {
+        let entry = ListEntry {
+            channel: channel.into(),
+            users,
+            topic: topic.into(),
+        };
+        if self.irc.push_list_entry(&entry) {
+            self.emit(Event::ListEntry(entry));
+        }
+        if let Some(list) = self.irc.expire_list() {
+            self.emit(Event::ListEnd(list));
+        }
+    }
+
+    fn end_motd(&mut self, received: bool) {
+        let lines = mem::take(&mut self.motd);
+        self.irc.set_motd(if received { Some(lines.join("\n")) } else { None });
+        let motd = self.irc.motd();
+        self.emit(Event::Motd(motd));
+
+        if !self.registered {
+            self.registered = true;
+            self.autojoin();
+            if let Some(message) = self.irc.away_message() {
+                let _ = self.irc.away(Some(&message));
+            }
+            self.start_presence();
+            self.start_lag();
+        }
+    }
+
+    fn autojoin(&mut self) {
+        let casemapping = self.irc.casemapping();
+        let mut channels: Vec<(String, Option<String>)> = Vec::new();
+
+        let autojoin = self.settings.autojoin.iter().map(|&(c, k)| (c.to_string(), k.map(String::from)));
+        let rejoin = mem::take(&mut self.rejoin).into_iter().map(|c| {
+            let key = self.irc.key(&c);
+            (c, key)
+        });
+        for (channel, key) in autojoin.chain(rejoin) {
+            if !channels.iter().any(|(c, _)| casemapping.eq(c, &channel)) {
+                channels.push((channel, key));
+            }
+        }
+
+        let max_targets = self.irc.isupport().max_targets("JOIN");
+        for (targets, keys) in join_batches(&channels, max_targets) {
+            let _ = self.irc.join(&targets, keys.as_deref());
+        }
+
+        // The server answers in order, so once it answers this PING, the channels it did not
+        // let us back in, or did not answer for at all, are dropped.
+        if self.irc.channels().iter().any(|c| c.is_desynced()) {
+            let token = format!("REJOIN{}", *self.irc.connection.lock().unwrap());
+            if self.irc.ping(&token).is_ok() {
+                self.rejoin_fence = Some(token);
+            }
+        }
+    }
+
+    // Drop the channels kept from before the reconnection which we could not rejoin.
+    fn drop_desynced(&mut self) {
+        for channel in self.irc.channels() {
+            if channel.is_desynced() {
+                self.irc.end_join(channel.name());
+                self.irc.remove_channel(&self.irc.normalize(channel.name()));
             }
         }
     }
 
+    fn start_presence(&mut self) {
+        let method = if self.irc.isupport().contains("MONITOR") { Method::Monitor } else { Method::Ison };
+        let generation = self.irc.start_presence(method);
+        // With MONITOR, the nicknames refused by the server are still polled with ISON.
+        let irc = self.irc.clone();
+        let interval = self.settings.ison_interval;
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if irc.is_closed() || !irc.poll_ison(generation) {
+                break;
+            }
+        });
+    }
+
+    fn registration_timeout(&self, connection: u64) {
+        let timeout = some_or_return!(self.settings.registration_timeout);
+        let irc = self.irc.clone();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            irc.check_registration(connection);
+        });
+    }
+
+    fn start_lag(&mut self) {
+        let (generation, interval) = some_or_return!(self.irc.start_lag());
+        let irc = self.irc.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if irc.is_closed() || !irc.ping_lag(generation) {
+                break;
+            }
+        });
+    }
+
+    fn presence(&mut self, nickname: &str, online: bool) {
+        if self.irc.set_online(nickname, online) {
+            self.emit(presence_event(nickname.into(), online));
+        }
+    }
+
+    fn end_ban_list(&mut self, channel_name: &str) {
+        let channel = some_or_return!(self.irc.channel(channel_name));
+        channel.finish_bans();
+    }
+
+    fn invite_list(&mut self, channel_name: &str, mask: &str, set_by: Option<String>, set_at: Option<u64>) {
+        let channel = some_or_return!(self.irc.channel(channel_name));
+        channel.add_pending_invite(Ban {
+            mask: Hostmask::parse(mask),
+            set_by,
+            set_at,
+        });
+    }
+
+    fn end_invite_list(&mut self, channel_name: &str) {
+        let channel = some_or_return!(self.irc.channel(channel_name));
+        channel.finish_invites();
+    }
+
+    // Only operators can see the invite list, and only if the server has one.
+    fn request_invites(&self, channel_name: &str) {
+        if let Some(mode) = self.irc.isupport().invex() {
+            let _ = self.irc.mode(channel_name, &format!("+{}", mode), &[]);
+        }
+    }
+
+    fn invite(&mut self, msg: &Message) {
+        let inviter = match msg.prefix {
+            Some(Prefix::User(ref user)) => user,
+            _ => return,
+        };
+        let target = some_or_return!(msg.args.first());
+        let channel = some_or_return!(msg.args.get(1));
+
+        if self.irc.is_me(target) {
+            let casemapping = self.irc.casemapping();
+            let allowed = self.settings.join_on_invite.iter()
+                .any(|mask| Hostmask::parse(mask).matches_user(inviter, casemapping));
+            if allowed && self.irc.channel(channel).is_none() {
+                let key = self.irc.key(channel);
+                let _ = self.irc.join(channel, key.as_deref());
+            }
+        }
+        self.emit(Event::Invite { inviter: inviter.clone(), target: target.clone(), channel: channel.clone() });
+    }
+
+}
+
+fn presence_event(nickname: String, online: bool) -> Event {
+    if online {
+        Event::UserOnline(nickname)
+    } else {
+        Event::UserOffline(nickname)
+    }
+}
+
+fn send_monitor<W: IrcWrite>(w: &W, op: &str, nicknames: &[String]) -> Result<(), Error> {
+    for batch in presence::batches(nicknames, MAX_BATCH_LENGTH, usize::MAX) {
+        w.command("MONITOR", &[op, &batch.join(",")], None)?;
+    }
+    Ok(())
+}
+
+// Nicknames are sent as separate parameters, and messages have at most 15 parameters.
+//
+// The nicknames are recorded by `Irc::write` when the command actually goes out.
+fn send_ison<W: IrcWrite>(w: &W, nicknames: &[String]) -> Result<(), Error> {
+    for batch in presence::batches(nicknames, MAX_BATCH_LENGTH, 14) {
+        let params: Vec<&str> = batch.iter().map(|n| &n[..]).collect();
+        w.command("ISON", &params, None)?;
+    }
+    Ok(())
+}
+
+// Get the nicknames asked by an ISON command, None for other commands.
+fn ison_nicknames(raw: &str) -> Option<Vec<String>> {
+    let (command, params) = raw.split_once(' ').unwrap_or((raw, ""));
+    if !command.eq_ignore_ascii_case("ISON") {
+        return None;
+    }
+    Some(params.split_whitespace().map(|n| n.trim_start_matches(':')).filter(|n| !n.is_empty()).map(String::from).collect())
+}
+
+// Build the changes of a single mode, one for each parameter.
+fn mode_changes(set: bool, mode: char, params: &[&str]) -> Vec<ModeChange> {
+    params.iter().map(|p| ModeChange { set, mode, param: Some(p.to_string()) }).collect()
+}
+
+fn quiet_changes(isupport: &ISupport, set: bool, masks: &[&str]) -> Result<Vec<ModeChange>, Error> {
+    let (mode, prefix) = isupport.quiet_mode().ok_or_else(|| Error::Unsupported("quiets".into()))?;
+    Ok(masks.iter().map(|m| ModeChange {
+        set,
+        mode,
+        param: Some(match Hostmask::parse(m) {
+            Hostmask::Extban(ref extban) if !prefix.is_empty() && extban.starts_with(&prefix[..]) => extban.clone(),
+            mask => format!("{}{}", prefix, mask),
+        }),
+    }).collect())
+}
+
+// Group mode changes into MODE commands, as `(modes, params)` parameters.
+fn mode_batches(changes: &[ModeChange], max_modes: Option<usize>) -> Vec<(String, Vec<String>)> {
+    let mut batches = Vec::new();
+    let mut modes = String::new();
+    let mut params = Vec::new();
+    let mut set = None;
+    let mut length = 0;
+
+    for change in changes {
+        let change_length = change.param.as_ref().map(|p| p.len() + 1).unwrap_or(0) + 2;
+        let full = change.param.is_some() && max_modes.map(|m| params.len() >= m.max(1)).unwrap_or(false);
+        if !modes.is_empty() && (full || length + change_length > MAX_BATCH_LENGTH) {
+            batches.push((mem::take(&mut modes), mem::take(&mut params)));
+            set = None;
+            length = 0;
+        }
+        if set != Some(change.set) {
+            modes.push(if change.set { '+' } else { '-' });
+            set = Some(change.set);
+        }
+        modes.push(change.mode);
+        params.extend(change.param.clone());
+        length += change_length;
+    }
+    if !modes.is_empty() {
+        batches.push((modes, params));
+    }
+    batches
+}
+
+// Longest list of targets in a single JOIN or MODE, to stay far from the line length limit.
+const MAX_BATCH_LENGTH: usize = 400;
+
+// Group channels into JOIN commands, as `(channels, keys)` parameters.
+//
+// Channels with a key come first in each command, since keys are matched to channels in order.
+fn join_batches(channels: &[(String, Option<String>)], max_targets: Option<usize>) -> Vec<(String, Option<String>)> {
+    let mut sorted: Vec<&(String, Option<String>)> = channels.iter().collect();
+    sorted.sort_by_key(|&(_, key)| key.is_none());
+
+    let mut batches: Vec<Vec<&(String, Option<String>)>> = Vec::new();
+    let mut length = 0;
+    for entry in sorted {
+        let entry_length = entry.0.len() + entry.1.as_ref().map(|k| k.len() + 1).unwrap_or(0) + 1;
+        let full = match batches.last() {
+            Some(batch) => max_targets.map(|m| batch.len() >= m).unwrap_or(false) || length + entry_length > MAX_BATCH_LENGTH,
+            None => true,
+        };
+        if full {
+            batches.push(Vec::new());
+            length = 0;
+        }
+        batches.last_mut().unwrap().push(entry);
+        length += entry_length;
+    }
+
+    batches.into_iter().map(|batch| {
+        let targets = batch.iter().map(|&(c, _)| &c[..]).collect::<Vec<_>>().join(",");
+        let keys = batch.iter().filter_map(|&(_, k)| k.as_deref()).collect::<Vec<_>>();
+        (targets, if keys.is_empty() { None } else { Some(keys.join(",")) })
+    }).collect()
+}
+
+fn prefix_name(prefix: &Prefix) -> String {
+    match *prefix {
+        Prefix::User(ref user) => Hostmask::from_user(user).to_string(),
+        Prefix::Server(ref server) => server.clone(),
+    }
 }
 
 #[test]
@@ -777,7 +2560,7 @@ fn test_user_from_raw_owner() {
 
 #[test]
 fn test_channel() {
-    let channel = Channel::new("#testchannel");
+    let channel = Channel::new("#testchannel", CaseMapping::Rfc1459);
     channel.set_topic("ABC DEF");
 
     let usr1 = Arc::new(ChannelUser::new("abc1", ChannelUserStatus::Normal));
@@ -791,3 +2574,346 @@ fn test_channel() {
     assert_eq!(channel.user("abc1").unwrap().nickname(), usr1.nickname());
     assert_eq!(channel.user("abc2").unwrap().nickname(), usr2.nickname());
 }
+
+#[test]
+fn test_channel_bans() {
+    let channel = Channel::new("#testchannel", CaseMapping::Rfc1459);
+    let ban = |mask| Ban { mask: Hostmask::parse(mask), set_by: None, set_at: None };
+
+    channel.add_pending_ban(ban("*!*@*.example.com"));
+    channel.add_pending_ban(ban("Bad[Nick]"));
+    channel.finish_bans();
+    channel.add_ban(ban("bad{nick}"));
+    assert_eq!(channel.bans().len(), 2);
+
+    let user = PrefixUser { nickname: "bad{nick}".into(), username: "u".into(), hostname: "h".into() };
+    assert!(channel.is_banned(&user));
+    channel.remove_ban(&Hostmask::parse("BAD[NICK]!*@*"));
+    assert!(!channel.is_banned(&user));
+    assert_eq!(channel.bans(), vec![ban("*!*@*.example.com")]);
+}
+
+#[test]
+fn test_channel_invites() {
+    let channel = Channel::new("#testchannel", CaseMapping::Rfc1459);
+    let invite = |mask| Ban { mask: Hostmask::parse(mask), set_by: None, set_at: None };
+    let user = PrefixUser { nickname: "friend".into(), username: "u".into(), hostname: "friend.org".into() };
+
+    channel.add_pending_invite(invite("*!*@friend.org"));
+    assert!(!channel.is_invited(&user));
+    channel.finish_invites();
+    assert!(channel.is_invited(&user));
+    channel.remove_invite(&Hostmask::parse("*!*@FRIEND.ORG"));
+    assert!(channel.invites().is_empty());
+    assert!(channel.bans().is_empty());
+}
+
+#[test]
+fn test_channel_resync() {
+    let channel = Channel::new("#testchannel", CaseMapping::Rfc1459);
+    let nicknames = |users: &[Arc<ChannelUser>]| users.iter().map(|u| u.nickname().to_string()).collect::<Vec<_>>();
+
+    for raw in &["@me", "alice", "bob"] {
+        channel.add_pending_name(ChannelUser::from_raw(raw));
+    }
+    channel.finish_names();
+    let alice = channel.user("alice").unwrap();
+
+    channel.desync();
+    assert!(channel.is_desynced());
+    for raw in &["me", "+alice", "carol"] {
+        channel.add_pending_name(ChannelUser::from_raw(raw));
+    }
+    let (joined, left) = channel.finish_names();
+    assert!(!channel.is_desynced());
+    assert_eq!(nicknames(&joined), vec!["carol"]);
+    assert_eq!(nicknames(&left), vec!["bob"]);
+    assert_eq!(nicknames(&channel.users()), vec!["me", "alice", "carol"]);
+    assert!(Arc::ptr_eq(&alice, &channel.user("alice").unwrap()));
+    assert_eq!(alice.status(), ChannelUserStatus::Voice);
+}
+
+#[test]
+fn test_caps_negotiation() {
+    let server = TestServer::new();
+    let settings = server.settings().cap("account-notify").sasl("jilles", "sesame");
+    let (mut dispatch, mut client) = server.connect(Chain::new(), settings);
+    let irc = dispatch.irc();
+    assert_eq!(client.line(), "CAP LS 302");
+    assert_eq!(irc.status(), ConnectionStatus::NegotiatingCaps);
+
+    dispatch.feed(&message(":server CAP * LS * :multi-prefix sasl=PLAIN"));
+    dispatch.feed(&message(":server CAP * LS :account-notify away-notify"));
+    assert_eq!(client.until("CAP REQ"), "CAP REQ :account-notify sasl");
+
+    dispatch.feed(&message(":server CAP * ACK :account-notify sasl"));
+    assert_eq!(client.line(), "AUTHENTICATE PLAIN");
+    assert_eq!(irc.status(), ConnectionStatus::Authenticating);
+
+    dispatch.feed(&message("AUTHENTICATE +"));
+    assert_eq!(client.line(), "AUTHENTICATE amlsbGVzAGppbGxlcwBzZXNhbWU=");
+    dispatch.feed(&message(":server 903 me :SASL authentication successful"));
+    assert_eq!(client.line(), "CAP END");
+    assert_eq!(irc.status(), ConnectionStatus::Registering);
+
+    dispatch.feed(&message(":server 001 me :Welcome"));
+    assert_eq!(irc.status(), ConnectionStatus::Registered);
+    assert!(irc.has_cap("sasl"));
+    assert!(!irc.has_cap("away-notify"));
+}
+
+#[test]
+fn test_queue_cleared_on_disconnect() {
+    let server = TestServer::new();
+    let (mut dispatch, _client) = server.dispatch();
+    let irc = dispatch.irc();
+    irc.privmsg("alice", "hello").unwrap();
+    assert_eq!(irc.queue.lock().unwrap().len(), 1);
+
+    dispatch.feed(&loirc::Event::Disconnected);
+    assert!(irc.queue.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_interleaved_ison() {
+    let server = TestServer::new();
+    let settings = server.settings().ison_interval(Duration::from_secs(3600));
+    let (mut dispatch, mut client) = server.connect(Chain::new(), settings);
+    let irc = dispatch.irc();
+    dispatch.feed(&message(":server 001 me :Welcome"));
+    dispatch.feed(&message(":server 376 me :End of /MOTD command."));
+
+    irc.raw("ISON bob").unwrap();
+    irc.watch(&["alice"]).unwrap();
+    assert_eq!(client.until("ISON"), "ISON bob");
+    assert_eq!(client.until("ISON"), "ISON alice");
+
+    // The first reply answers the ISON of the user, not ours.
+    dispatch.feed(&message(":server 303 me :bob"));
+    assert_eq!(irc.is_online("alice"), None);
+    dispatch.feed(&message(":server 303 me :alice"));
+    assert_eq!(irc.is_online("alice"), Some(true));
+
+    irc.raw("ISON :carol alice").unwrap();
+    dispatch.feed(&message(":server 303 me :carol"));
+    assert_eq!(irc.is_online("alice"), Some(false));
+}
+
+#[test]
+fn test_stale_desynced_channels() {
+    let server = TestServer::new();
+    let (mut dispatch, mut client) = server.dispatch();
+    let irc = dispatch.irc();
+    dispatch.feed(&message(":server 001 me :Welcome"));
+    dispatch.feed(&message(":server 376 me :End of /MOTD command."));
+    for channel in &["#kept", "#lost"] {
+        dispatch.feed(&message(&format!(":me!m@host JOIN {}", channel)));
+        dispatch.feed(&message(&format!(":server 353 me = {} :me alice", channel)));
+        dispatch.feed(&message(&format!(":server 366 me {} :End of /NAMES list.", channel)));
+    }
+
+    dispatch.feed(&loirc::Event::Disconnected);
+    dispatch.feed(&loirc::Event::Reconnected);
+    dispatch.feed(&message(":server 001 me :Welcome"));
+    dispatch.feed(&message(":server 376 me :End of /MOTD command."));
+    assert!(irc.channel("#lost").unwrap().is_desynced());
+
+    // Only #kept is rejoined, the server never answers for #lost.
+    let ping = client.until("PING");
+    let token = ping.trim_start_matches("PING :");
+    dispatch.feed(&message(":me!m@host JOIN #kept"));
+    dispatch.feed(&message(":server 353 me = #kept :me alice"));
+    dispatch.feed(&message(":server 366 me #kept :End of /NAMES list."));
+    dispatch.feed(&message(&format!(":server PONG server :{}", token)));
+    assert!(!irc.channel("#kept").unwrap().is_desynced());
+    assert!(irc.channel("#lost").is_none());
+}
+
+#[test]
+fn test_desynced_channel_errors() {
+    let server = TestServer::new();
+    let (mut dispatch, _client) = server.dispatch();
+    let irc = dispatch.irc();
+
+    dispatch.feed(&message(":server 353 me = #chan :me alice"));
+    dispatch.feed(&message(":server 366 me #chan :End of /NAMES list."));
+    let channel = irc.channel("#chan").unwrap();
+    irc.desync_channels();
+
+    dispatch.feed(&message(":server 404 me #chan :Cannot send to channel"));
+    dispatch.feed(&message(":server 474 me #chan :Cannot join channel (+b)"));
+    assert!(Arc::ptr_eq(&channel, &irc.channel("#chan").unwrap()));
+
+    irc.joining("#chan", None);
+    dispatch.feed(&message(":server 474 me #chan :Cannot join channel (+b)"));
+    assert!(irc.channel("#chan").is_none());
+}
+
+#[test]
+fn test_casemapped_names() {
+    let server = TestServer::new();
+    let (mut dispatch, _client) = server.dispatch();
+    let irc = dispatch.irc();
+
+    irc.joining("#foo[bar]", Some("key"));
+    assert!(irc.is_joining("#FOO{BAR}"));
+    assert_eq!(irc.key("#FOO{BAR}"), Some("key".into()));
+
+    dispatch.feed(&message(":server 353 me = #foo[bar] :me alice[m]"));
+    dispatch.feed(&message(":server 366 me #foo[bar] :End of /NAMES list."));
+    let channel = irc.channel("#FOO{BAR}").unwrap();
+    assert_eq!(channel.name(), "#foo[bar]");
+    assert!(channel.user("ALICE{M}").is_some());
+    assert!(irc.is_me("ME"));
+
+    dispatch.feed(&message(":ALICE{M}!a@host ACCOUNT alice"));
+    assert_eq!(irc.account("alice[m]"), Some("alice".into()));
+    dispatch.feed(&message(":alice{m}!a@host PART #FOO{BAR}"));
+    assert!(channel.user("alice[m]").is_none());
+}
+
+#[test]
+fn test_check_target() {
+    assert!(check_target("#channel").is_ok());
+    assert!(check_target("nick[m]").is_ok());
+    assert!(check_target("").is_err());
+    assert!(check_target("x :#other").is_err());
+    assert!(check_target(":nick").is_err());
+    assert!(check_target("nick\0").is_err());
+}
+
+#[test]
+fn test_serialize() {
+    assert_eq!(serialize("USER", &["bob", "8", "*"], Some("Bob Smith")).unwrap(), "USER bob 8 * :Bob Smith");
+    assert_eq!(serialize("PART", &["#a"], None).unwrap(), "PART #a");
+    assert_eq!(serialize("QUIT", &[], Some("")).unwrap(), "QUIT :");
+    assert!(serialize("JOIN", &["#a", "key :x"], None).is_err());
+    assert!(serialize("PASS", &[":secret"], None).is_err());
+    assert!(serialize("PING", &[""], None).is_err());
+    assert!(serialize("PRIVMSG", &["#a"], Some("a\0b")).is_err());
+}
+
+#[cfg(test)]
+struct Recorder(Mutex<Vec<String>>);
+
+#[cfg(test)]
+impl IrcWrite for Recorder {
+    fn raw<S: AsRef<str>>(&self, raw: S) -> Result<(), Error> {
+        self.0.lock().unwrap().push(raw.as_ref().into());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_trailing_tokens() {
+    let recorder = Recorder(Mutex::new(Vec::new()));
+    recorder.pong("a b").unwrap();
+    recorder.pong("").unwrap();
+    recorder.ping("irc.example.com").unwrap();
+    recorder.pass(":x").unwrap();
+    assert_eq!(*recorder.0.lock().unwrap(), vec!["PONG :a b", "PONG :", "PING :irc.example.com", "PASS ::x"]);
+}
+
+#[test]
+fn test_join_batches() {
+    let channels: Vec<(String, Option<String>)> = vec![
+        ("#a".into(), None),
+        ("#b".into(), Some("kb".into())),
+        ("#c".into(), None),
+        ("#d".into(), Some("kd".into())),
+    ];
+    assert_eq!(join_batches(&channels, None), vec![("#b,#d,#a,#c".into(), Some("kb,kd".into()))]);
+    assert_eq!(join_batches(&channels, Some(3)), vec![
+        ("#b,#d,#a".into(), Some("kb,kd".into())),
+        ("#c".into(), None),
+    ]);
+    assert_eq!(join_batches(&[], Some(3)), vec![]);
+
+    let many: Vec<(String, Option<String>)> = (0..100).map(|i| (format!("#channel{}", i), None)).collect();
+    assert!(join_batches(&many, None).iter().all(|(targets, _)| targets.len() <= MAX_BATCH_LENGTH));
+}
+
+#[test]
+fn test_join_failure() {
+    assert_eq!(JoinFailure::from_code(&Code::ErrBadchannelkey), Some(JoinFailure::BadKey));
+    assert_eq!(JoinFailure::from_code(&Code::ErrNochanmodes), Some(JoinFailure::RegisteredOnly));
+    assert_eq!(JoinFailure::from_code(&Code::ErrNosuchnick), None);
+}
+
+#[test]
+fn test_mode_batches() {
+    let mut changes = mode_changes(true, 'o', &["a", "b", "c", "d"]);
+    changes.push(ModeChange { set: false, mode: 'l', param: None });
+    changes.extend(mode_changes(false, 'v', &["e"]));
+
+    assert_eq!(mode_batches(&changes, Some(3)), vec![
+        ("+ooo".into(), vec!["a".into(), "b".into(), "c".into()]),
+        ("+o-lv".into(), vec!["d".into(), "e".into()]),
+    ]);
+    assert_eq!(mode_batches(&changes, None).len(), 1);
+    assert_eq!(mode_batches(&[], Some(3)), vec![]);
+
+    let mut isupport = ISupport::default();
+    assert!(quiet_changes(&isupport, true, &["bob"]).is_err());
+    isupport.update(&["EXTBAN=~,q"]);
+    assert_eq!(quiet_changes(&isupport, true, &["bob"]).unwrap()[0].param, Some("~q:bob!*@*".into()));
+    assert_eq!(quiet_changes(&isupport, true, &["~q:bob!*@*"]).unwrap()[0].param, Some("~q:bob!*@*".into()));
+    assert_eq!(quiet_changes(&isupport, true, &["$a:bob"]).unwrap()[0].param, Some("~q:$a:bob".into()));
+}
+
+#[test]
+fn test_extbans() {
+    let recorder = Recorder(Mutex::new(Vec::new()));
+    recorder.ban("#chan", &["$a:account", "~q:bob!*@*", "bob"]).unwrap();
+    recorder.unban("#chan", &["$a:account"]).unwrap();
+    assert_eq!(*recorder.0.lock().unwrap(), vec!["MODE #chan +bbb $a:account ~q:bob!*@* bob!*@*", "MODE #chan -b $a:account"]);
+
+    let server = TestServer::new();
+    let (mut dispatch, _client) = server.dispatch();
+    let irc = dispatch.irc();
+    dispatch.feed(&message(":server 353 me = #chan :me alice"));
+    dispatch.feed(&message(":server 366 me #chan :End of /NAMES list."));
+    dispatch.feed(&message(":op!o@host MODE #chan +bb $a:alice *!*@*"));
+    let channel = irc.channel("#chan").unwrap();
+    let masks: Vec<Hostmask> = channel.bans().into_iter().map(|b| b.mask).collect();
+    assert_eq!(masks, vec![Hostmask::Extban("$a:alice".into()), Hostmask::parse("*!*@*")]);
+
+    dispatch.feed(&message(":op!o@host MODE #chan -b *!*@*"));
+    let alice = PrefixUser { nickname: "alice".into(), username: "a".into(), hostname: "host".into() };
+    assert!(!channel.is_banned(&alice));
+    dispatch.feed(&message(":op!o@host MODE #chan -b $A:ALICE"));
+    assert!(channel.bans().is_empty());
+}
+
+#[test]
+fn test_disconnect_reason() {
+    let kind = |message| DisconnectReason::parse(message).kind;
+    assert_eq!(kind("Closing Link: host (Excess Flood)"), DisconnectKind::Flood);
+    assert_eq!(kind("Closing Link: host (K-Lined)"), DisconnectKind::KLined);
+    assert_eq!(kind("Closing Link: host (Ping timeout: 240 seconds)"), DisconnectKind::PingTimeout);
+    assert_eq!(kind("Closing Link: host (Registration Timeout)"), DisconnectKind::RegistrationTimeout);
+    assert_eq!(kind("Trying to reconnect too fast."), DisconnectKind::Throttled);
+    assert_eq!(kind("Closing Link: host (Quit: bye)"), DisconnectKind::Other);
+    assert_eq!(kind("Closing Link: host (You are banned from this server- spam)"), DisconnectKind::KLined);
+    assert_eq!(kind("Closing Link: host (G-Lined: spam)"), DisconnectKind::KLined);
+    assert_eq!(kind("Closing Link: host [Quit: got banned from #foo]"), DisconnectKind::Other);
+    assert_eq!(kind("Closing Link: host (Quit: K-Lined lol)"), DisconnectKind::Other);
+    assert_eq!(kind("Closing Link: host (Quit: Excess Flood)"), DisconnectKind::Other);
+    assert_eq!(kind("Closing Link: host [Quit: K-Lined]"), DisconnectKind::Other);
+}
+
+#[test]
+fn test_registration_status() {
+    let next = |status: ConnectionStatus, command, params| status.after_sending(command, params);
+    assert_eq!(next(ConnectionStatus::Connecting, "NICK", "alice"), Some(ConnectionStatus::Registering));
+    assert_eq!(next(ConnectionStatus::Connecting, "PASS", "secret"), None);
+    assert_eq!(next(ConnectionStatus::Registering, "USER", "alice 8 *"), None);
+    assert_eq!(next(ConnectionStatus::Connecting, "CAP", "LS 302"), Some(ConnectionStatus::NegotiatingCaps));
+    assert_eq!(next(ConnectionStatus::Registering, "CAP", "req :sasl"), Some(ConnectionStatus::NegotiatingCaps));
+    assert_eq!(next(ConnectionStatus::NegotiatingCaps, "NICK", "alice"), None);
+    assert_eq!(next(ConnectionStatus::NegotiatingCaps, "AUTHENTICATE", "PLAIN"), Some(ConnectionStatus::Authenticating));
+    assert_eq!(next(ConnectionStatus::Authenticating, "AUTHENTICATE", "+"), None);
+    assert_eq!(next(ConnectionStatus::Authenticating, "CAP", "END"), Some(ConnectionStatus::Registering));
+    assert!(ConnectionStatus::Registering.is_registering());
+    assert!(!ConnectionStatus::Registered.is_registering());
+}
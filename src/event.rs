@@ -0,0 +1,589 @@
+//! High-level events, mirroring the callbacks of the `Listener` trait.
+//!
+//! Instead of implementing `Listener`, events can be pulled from an iterator:
+//!
+//! ```ignore
+//! let mut events = Settings::new("irc.freenode.net:6667", "hiirc").events()?;
+//! let irc = events.irc();
+//! for event in events {
+//!     match event {
+//!         Event::Welcome => { irc.join("#hiirc", None); }
+//!         Event::ChannelMsg { channel, message, .. } => println!("{}: {}", channel.name(), message),
+//!         _ => {}
+//!     }
+//! }
+//! ```
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use loirc::{self, Reader};
+use chain::Chain;
+use core::{Dispatch, Error};
+use list::{ChannelList, ListEntry};
+use replies::Reply;
+use settings::Settings;
+use {Channel, ChannelUser, ChannelUserStatus, Code, DisconnectReason, Irc, JoinFailure, Listener, Message, PrefixUser};
+
+/// An owned version of every `Listener` callback.
+///
+/// Each variant carries the same information as the matching callback, so that
+/// events can be moved across threads or consumed outside of a `Listener`.
+#[derive(Debug)]
+pub enum Event {
+    /// See `Listener::any`.
+    Any(loirc::Event),
+    /// See `Listener::msg`.
+    Msg(Message),
+    /// See `Listener::error_msg`.
+    ErrorMsg {
+        /// Code of the error.
+        code: Code,
+        /// The error message.
+        err: Message,
+    },
+    /// See `Listener::reply`.
+    Reply(Reply),
+    /// See `Listener::close`.
+    Close(String),
+    /// See `Listener::disconnect`.
+    Disconnect(Option<DisconnectReason>),
+    /// See `Listener::reconnecting`.
+    Reconnecting,
+    /// See `Listener::reconnect`.
+    Reconnect,
+    /// See `Listener::welcome`.
+    Welcome,
+    /// See `Listener::motd`.
+    Motd(Option<Arc<String>>),
+    /// See `Listener::list_entry`.
+    ListEntry(ListEntry),
+    /// See `Listener::list_end`.
+    ListEnd(ChannelList),
+    /// See `Listener::channel_join`.
+    ChannelJoin(Arc<Channel>),
+    /// See `Listener::channel_resync`.
+    ChannelResync {
+        /// The channel which was synchronized again.
+        channel: Arc<Channel>,
+        /// The users who joined while we were disconnected.
+        joined: Vec<Arc<ChannelUser>>,
+        /// The users who left while we were disconnected.
+        left: Vec<Arc<ChannelUser>>,
+    },
+    /// See `Listener::invite`.
+    Invite {
+        /// The user who sent the invite.
+        inviter: PrefixUser,
+        /// The invited user.
+        target: String,
+        /// The channel.
+        channel: String,
+    },
+    /// See `Listener::join_failed`.
+    JoinFailed {
+        /// The channel.
+        channel: String,
+        /// Why we couldn't join.
+        reason: JoinFailure,
+        /// The error text sent by the server.
+        message: String,
+    },
+    /// See `Listener::user_join`.
+    UserJoin {
+        /// The channel.
+        channel: Arc<Channel>,
+        /// The user who joined.
+        user: Arc<ChannelUser>,
+    },
+    /// See `Listener::user_part`.
+    UserPart {
+        /// The channel.
+        channel: Arc<Channel>,
+        /// The user who parted.
+        user: Arc<ChannelUser>,
+    },
+    /// See `Listener::user_quit`.
+    UserQuit(String),
+    /// See `Listener::channel_msg`.
+    ChannelMsg {
+        /// The channel.
+        channel: Arc<Channel>,
+        /// The sender of the message.
+        sender: Arc<ChannelUser>,
+        /// The text of the message.
+        message: String,
+    },
+    /// See `Listener::channel_notice`.
+    ChannelNotice {
+        /// The channel.
+        channel: Arc<Channel>,
+        /// The sender of the notice.
+        sender: Arc<ChannelUser>,
+        /// The text of the notice.
+        message: String,
+    },
+    /// See `Listener::private_msg`.
+    PrivateMsg {
+        /// The sender of the message.
+        sender: PrefixUser,
+        /// The text of the message.
+        message: String,
+    },
+    /// See `Listener::private_notice`.
+    PrivateNotice {
+        /// The sender of the notice.
+        sender: PrefixUser,
+        /// The text of the notice.
+        message: String,
+    },
+    /// See `Listener::topic`.
+    Topic {
+        /// The channel.
+        channel: Arc<Channel>,
+        /// The topic, if any.
+        topic: Option<Arc<String>>,
+    },
+    /// See `Listener::topic_change`.
+    TopicChange {
+        /// The channel.
+        channel: Arc<Channel>,
+        /// The new topic, if any.
+        topic: Option<Arc<String>>,
+    },
+    /// See `Listener::nick_change`.
+    NickChange {
+        /// The old nickname.
+        oldnick: String,
+        /// The new nickname.
+        newnick: String,
+    },
+    /// See `Listener::user_away`.
+    UserAway {
+        /// Nickname of the user.
+        nickname: String,
+        /// Away message of the user.
+        message: String,
+    },
+    /// See `Listener::lag`.
+    Lag(Duration),
+    /// See `Listener::user_online`.
+    UserOnline(String),
+    /// See `Listener::user_offline`.
+    UserOffline(String),
+    /// See `Listener::kick`.
+    Kick {
+        /// The channel.
+        channel: Arc<Channel>,
+        /// The user who was kicked.
+        user: Arc<ChannelUser>,
+    },
+    /// See `Listener::ping`.
+    Ping(String),
+    /// See `Listener::pong`.
+    Pong(String),
+    /// See `Listener::user_mode_change`.
+    UserModeChange {
+        /// The channel.
+        channel: Arc<Channel>,
+        /// The user whose mode changed.
+        user: Arc<ChannelUser>,
+        /// Status before the change.
+        old_status: ChannelUserStatus,
+        /// Status after the change.
+        new_status: ChannelUserStatus,
+    },
+}
+
+impl Event {
+
+    /// Call the callback of the listener matching this event.
+    pub fn deliver(&self, irc: Arc<Irc>, listener: &mut dyn Listener) {
+        match *self {
+            Event::Any(ref event) => listener.any(irc, event),
+            Event::Msg(ref msg) => listener.msg(irc, msg),
+            Event::ErrorMsg { ref code, ref err } => listener.error_msg(irc, code, err),
+            Event::Reply(ref reply) => listener.reply(irc, reply),
+            Event::Close(ref reason) => listener.close(irc, reason),
+            Event::Disconnect(ref reason) => listener.disconnect(irc, reason.as_ref()),
+            Event::Reconnecting => listener.reconnecting(irc),
+            Event::Reconnect => listener.reconnect(irc),
+            Event::Welcome => listener.welcome(irc),
+            Event::Motd(ref motd) => listener.motd(irc, motd.clone()),
+            Event::ListEntry(ref entry) => listener.list_entry(irc, entry),
+            Event::ListEnd(ref list) => listener.list_end(irc, list),
+            Event::ChannelJoin(ref channel) => listener.channel_join(irc, channel.clone()),
+            Event::ChannelResync { ref channel, ref joined, ref left } => {
+                listener.channel_resync(irc, channel.clone(), joined, left)
+            }
+            Event::Invite { ref inviter, ref target, ref channel } => {
+                listener.invite(irc, inviter, target, channel)
+            }
+            Event::JoinFailed { ref channel, reason, ref message } => {
+                listener.join_failed(irc, channel, reason, message)
+            }
+            Event::UserJoin { ref channel, ref user } => {
+                listener.user_join(irc, channel.clone(), user.clone())
+            }
+            Event::UserPart { ref channel, ref user } => {
+                listener.user_part(irc, channel.clone(), user.clone())
+            }
+            Event::UserQuit(ref nickname) => listener.user_quit(irc, nickname),
+            Event::ChannelMsg { ref channel, ref sender, ref message } => {
+                listener.channel_msg(irc, channel.clone(), sender.clone(), message)
+            }
+            Event::ChannelNotice { ref channel, ref sender, ref message } => {
+                listener.channel_notice(irc, channel.clone(), sender.clone(), message)
+            }
+            Event::PrivateMsg { ref sender, ref message } => listener.private_msg(irc, sender, message),
+            Event::PrivateNotice { ref sender, ref message } => listener.private_notice(irc, sender, message),
+            Event::Topic { ref channel, ref topic } => listener.topic(irc, channel.clone(), topic.clone()),
+            Event::TopicChange { ref channel, ref topic } => {
+                listener.topic_change(irc, channel.clone(), topic.clone())
+            }
+            Event::NickChange { ref oldnick, ref newnick } => listener.nick_change(irc, oldnick, newnick),
+            Event::UserAway { ref nickname, ref message } => listener.user_away(irc, nickname, message),
+            Event::Lag(lag) => listener.lag(irc, lag),
+            Event::UserOnline(ref nickname) => listener.user_online(irc, nickname),
+            Event::UserOffline(ref nickname) => listener.user_offline(irc, nickname),
+            Event::Kick { ref channel, ref user } => listener.kick(irc, channel.clone(), user.clone()),
+            Event::Ping(ref server) => listener.ping(irc, server),
+            Event::Pong(ref server) => listener.pong(irc, server),
+            Event::UserModeChange { ref channel, ref user, old_status, new_status } => {
+                listener.user_mode_change(irc, channel.clone(), user.clone(), old_status, new_status)
+            }
+        }
+    }
+
+}
+
+// loirc's Event is not Clone because of the io::Error it may contain.
+pub(crate) fn clone_event(event: &loirc::Event) -> loirc::Event {
+    match *event {
+        loirc::Event::Closed(reason) => loirc::Event::Closed(reason),
+        loirc::Event::Disconnected => loirc::Event::Disconnected,
+        loirc::Event::Message(ref msg) => loirc::Event::Message(msg.clone()),
+        loirc::Event::ParseError(err) => loirc::Event::ParseError(err),
+        loirc::Event::Reconnected => loirc::Event::Reconnected,
+        loirc::Event::Reconnecting => loirc::Event::Reconnecting,
+        loirc::Event::ReconnectionError(ref err) => {
+            loirc::Event::ReconnectionError(io::Error::new(err.kind(), err.to_string()))
+        }
+    }
+}
+
+/// A listener which turns every callback into an `Event` and hands it to a closure.
+pub(crate) struct Sink<F> {
+    f: F,
+}
+
+impl<F> Sink<F> where F: FnMut(&Arc<Irc>, Event) {
+
+    pub fn new(f: F) -> Sink<F> {
+        Sink {
+            f,
+        }
+    }
+
+}
+
+impl<F> Listener for Sink<F> where F: FnMut(&Arc<Irc>, Event) {
+
+    fn any(&mut self, irc: Arc<Irc>, event: &loirc::Event) {
+        (self.f)(&irc, Event::Any(clone_event(event)));
+    }
+
+    fn msg(&mut self, irc: Arc<Irc>, msg: &Message) {
+        (self.f)(&irc, Event::Msg(msg.clone()));
+    }
+
+    fn error_msg(&mut self, irc: Arc<Irc>, code: &Code, err: &Message) {
+        (self.f)(&irc, Event::ErrorMsg { code: code.clone(), err: err.clone() });
+    }
+
+    fn reply(&mut self, irc: Arc<Irc>, reply: &Reply) {
+        (self.f)(&irc, Event::Reply(reply.clone()));
+    }
+
+    fn close(&mut self, irc: Arc<Irc>, reason: &str) {
+        (self.f)(&irc, Event::Close(reason.into()));
+    }
+
+    fn disconnect(&mut self, irc: Arc<Irc>, reason: Option<&DisconnectReason>) {
+        (self.f)(&irc, Event::Disconnect(reason.cloned()));
+    }
+
+    fn reconnecting(&mut self, irc: Arc<Irc>) {
+        (self.f)(&irc, Event::Reconnecting);
+    }
+
+    fn reconnect(&mut self, irc: Arc<Irc>) {
+        (self.f)(&irc, Event::Reconnect);
+    }
+
+    fn welcome(&mut self, irc: Arc<Irc>) {
+        (self.f)(&irc, Event::Welcome);
+    }
+
+    fn motd(&mut self, irc: Arc<Irc>, motd: Option<Arc<String>>) {
+        (self.f)(&irc, Event::Motd(motd));
+    }
+
+    fn list_entry(&mut self, irc: Arc<Irc>, entry: &ListEntry) {
+        (self.f)(&irc, Event::ListEntry(entry.clone()));
+    }
+
+    fn list_end(&mut self, irc: Arc<Irc>, list: &ChannelList) {
+        (self.f)(&irc, Event::ListEnd(list.clone()));
+    }
+
+    fn channel_join(&mut self, irc: Arc<Irc>, channel: Arc<Channel>) {
+        (self.f)(&irc, Event::ChannelJoin(channel));
+    }
+
+    fn channel_resync(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, joined: &[Arc<ChannelUser>],
+                      left: &[Arc<ChannelUser>]) {
+        (self.f)(&irc, Event::ChannelResync { channel, joined: joined.to_vec(), left: left.to_vec() });
+    }
+
+    fn invite(&mut self, irc: Arc<Irc>, inviter: &PrefixUser, target: &str, channel: &str) {
+        (self.f)(&irc, Event::Invite { inviter: inviter.clone(), target: target.into(), channel: channel.into() });
+    }
+
+    fn join_failed(&mut self, irc: Arc<Irc>, channel: &str, reason: JoinFailure, message: &str) {
+        (self.f)(&irc, Event::JoinFailed { channel: channel.into(), reason, message: message.into() });
+    }
+
+    fn user_join(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, user: Arc<ChannelUser>) {
+        (self.f)(&irc, Event::UserJoin { channel, user });
+    }
+
+    fn user_part(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, user: Arc<ChannelUser>) {
+        (self.f)(&irc, Event::UserPart { channel, user });
+    }
+
+    fn user_quit(&mut self, irc: Arc<Irc>, nickname: &str) {
+        (self.f)(&irc, Event::UserQuit(nickname.into()));
+    }
+
+    fn channel_msg(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, sender: Arc<ChannelUser>, message: &str) {
+        (self.f)(&irc, Event::ChannelMsg { channel, sender, message: message.into() });
+    }
+
+    fn channel_notice(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, sender: Arc<ChannelUser>, message: &str) {
+        (self.f)(&irc, Event::ChannelNotice { channel, sender, message: message.into() });
+    }
+
+    fn private_msg(&mut self, irc: Arc<Irc>, sender: &PrefixUser, message: &str) {
+        (self.f)(&irc, Event::PrivateMsg { sender: sender.clone(), message: message.into() });
+    }
+
+    fn private_notice(&mut self, irc: Arc<Irc>, sender: &PrefixUser, message: &str) {
+        (self.f)(&irc, Event::PrivateNotice { sender: sender.clone(), message: message.into() });
+    }
+
+    fn topic(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, topic: Option<Arc<String>>) {
+        (self.f)(&irc, Event::Topic { channel, topic });
+    }
+
+    fn topic_change(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, topic: Option<Arc<String>>) {
+        (self.f)(&irc, Event::TopicChange { channel, topic });
+    }
+
+    fn nick_change(&mut self, irc: Arc<Irc>, oldnick: &str, newnick: &str) {
+        (self.f)(&irc, Event::NickChange { oldnick: oldnick.into(), newnick: newnick.into() });
+    }
+
+    fn user_away(&mut self, irc: Arc<Irc>, nickname: &str, message: &str) {
+        (self.f)(&irc, Event::UserAway { nickname: nickname.into(), message: message.into() });
+    }
+
+    fn lag(&mut self, irc: Arc<Irc>, lag: Duration) {
+        (self.f)(&irc, Event::Lag(lag));
+    }
+
+    fn user_online(&mut self, irc: Arc<Irc>, nickname: &str) {
+        (self.f)(&irc, Event::UserOnline(nickname.into()));
+    }
+
+    fn user_offline(&mut self, irc: Arc<Irc>, nickname: &str) {
+        (self.f)(&irc, Event::UserOffline(nickname.into()));
+    }
+
+    fn kick(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, user: Arc<ChannelUser>) {
+        (self.f)(&irc, Event::Kick { channel, user });
+    }
+
+    fn ping(&mut self, irc: Arc<Irc>, server: &str) {
+        (self.f)(&irc, Event::Ping(server.into()));
+    }
+
+    fn pong(&mut self, irc: Arc<Irc>, server: &str) {
+        (self.f)(&irc, Event::Pong(server.into()));
+    }
+
+    fn user_mode_change(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, user: Arc<ChannelUser>,
+                        old_status: ChannelUserStatus, new_status: ChannelUserStatus) {
+        (self.f)(&irc, Event::UserModeChange { channel, user, old_status, new_status });
+    }
+
+}
+
+/// Blocking iterator over the events of a connection.
+///
+/// Events are produced by the same dispatcher as the `dispatch` function, so the state
+/// of `Irc` is kept up to date as the iteration goes. The iterator ends when the connection
+/// is closed.
+pub struct Events<'a> {
+    dispatch: Dispatch<'a>,
+    reader: Reader,
+    queue: Rc<RefCell<VecDeque<Event>>>,
+}
+
+impl<'a> Events<'a> {
+
+    /// Connect to the server and start collecting events.
+    pub fn new(settings: Settings<'a>) -> Result<Events<'a>, Error> {
+        let queue = Rc::new(RefCell::new(VecDeque::new()));
+        let sink_queue = queue.clone();
+        let sink = Sink::new(move |_: &Arc<Irc>, event: Event| {
+            sink_queue.borrow_mut().push_back(event);
+        });
+
+        let (dispatch, reader) = Dispatch::connect(Chain::new().listener(sink), settings)?;
+
+        Ok(Events {
+            dispatch,
+            reader,
+            queue,
+        })
+    }
+
+    /// Get the `Irc` object of this connection.
+    pub fn irc(&self) -> Arc<Irc> {
+        self.dispatch.irc()
+    }
+
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        loop {
+            if let Some(event) = self.queue.borrow_mut().pop_front() {
+                return Some(event);
+            }
+            match self.reader.recv() {
+                Ok(event) => self.dispatch.feed(&event),
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use testing::{message, TestServer};
+
+#[cfg(test)]
+const TEST_LINES: &[&str] = &[
+    ":server 001 me :Welcome",
+    ":me!m@host JOIN #chan",
+    ":server 353 me = #chan :me @alice",
+    ":server 366 me #chan :End of /NAMES list.",
+    ":alice!a@host PRIVMSG #chan :hello",
+    ":alice!a@host PRIVMSG me :psst",
+    ":alice!a@host NICK bob",
+    ":bob!a@host PART #chan",
+];
+
+#[test]
+fn test_events_iterator() {
+    let server = TestServer::new();
+    let recorded = Rc::new(RefCell::new(Vec::new()));
+    let sink_recorded = recorded.clone();
+    let sink = Sink::new(move |_: &Arc<Irc>, event: Event| {
+        sink_recorded.borrow_mut().push(format!("{:?}", event));
+    });
+    let (mut dispatch, _client) = server.connect(Chain::new().listener(sink), server.settings());
+    for line in TEST_LINES {
+        dispatch.feed(&message(line));
+    }
+    let expected = recorded.borrow().clone();
+    assert!(expected.iter().any(|e| e.starts_with("PrivateMsg") && e.contains("psst")));
+
+    let events = Events::new(server.settings()).unwrap();
+    let mut client = server.accept();
+    for line in TEST_LINES {
+        client.send(line);
+    }
+    let events: Vec<_> = events.take(expected.len()).map(|e| format!("{:?}", e)).collect();
+    assert_eq!(events, expected);
+}
+
+#[test]
+fn test_deliver() {
+    let server = TestServer::new();
+    let (mut dispatch, _client) = server.dispatch();
+    let irc = dispatch.irc();
+    dispatch.feed(&message(":server 353 me = #chan :me alice"));
+    dispatch.feed(&message(":server 366 me #chan :End of /NAMES list."));
+    let channel = irc.channel("#chan").unwrap();
+    let user = channel.user("alice").unwrap();
+    let msg = Message::parse(":alice!a@host PRIVMSG me :hi").unwrap();
+    let sender = PrefixUser { nickname: "alice".into(), username: "a".into(), hostname: "host".into() };
+    let entry = ListEntry { channel: "#chan".into(), users: 2, topic: "topic".into() };
+    let topic = Some(Arc::new("topic".to_string()));
+
+    let events = vec![
+        Event::Any(loirc::Event::Disconnected),
+        Event::Msg(msg.clone()),
+        Event::ErrorMsg { code: Code::ErrNosuchnick, err: msg.clone() },
+        Event::Reply(Reply::parse(&Message::parse(":server 305 me :Back").unwrap()).unwrap()),
+        Event::Close("closed".into()),
+        Event::Disconnect(Some(DisconnectReason::parse("Closing Link: host (Ping timeout)"))),
+        Event::Reconnecting,
+        Event::Reconnect,
+        Event::Welcome,
+        Event::Motd(topic.clone()),
+        Event::ListEntry(entry.clone()),
+        Event::ListEnd(ChannelList { entries: vec![entry], complete: true }),
+        Event::ChannelJoin(channel.clone()),
+        Event::ChannelResync { channel: channel.clone(), joined: vec![user.clone()], left: vec![] },
+        Event::Invite { inviter: sender.clone(), target: "me".into(), channel: "#chan".into() },
+        Event::JoinFailed { channel: "#chan".into(), reason: JoinFailure::Banned, message: "banned".into() },
+        Event::UserJoin { channel: channel.clone(), user: user.clone() },
+        Event::UserPart { channel: channel.clone(), user: user.clone() },
+        Event::UserQuit("alice".into()),
+        Event::ChannelMsg { channel: channel.clone(), sender: user.clone(), message: "hi".into() },
+        Event::ChannelNotice { channel: channel.clone(), sender: user.clone(), message: "hi".into() },
+        Event::PrivateMsg { sender: sender.clone(), message: "hi".into() },
+        Event::PrivateNotice { sender: sender.clone(), message: "hi".into() },
+        Event::Topic { channel: channel.clone(), topic: topic.clone() },
+        Event::TopicChange { channel: channel.clone(), topic: None },
+        Event::NickChange { oldnick: "alice".into(), newnick: "bob".into() },
+        Event::UserAway { nickname: "alice".into(), message: "gone".into() },
+        Event::Lag(Duration::from_millis(42)),
+        Event::UserOnline("alice".into()),
+        Event::UserOffline("alice".into()),
+        Event::Kick { channel: channel.clone(), user: user.clone() },
+        Event::Ping("server".into()),
+        Event::Pong("server".into()),
+        Event::UserModeChange { channel: channel.clone(), user: user.clone(),
+                                old_status: ChannelUserStatus::Normal, new_status: ChannelUserStatus::Operator },
+    ];
+
+    // The sink turns the callback back into an event, which must be the one delivered.
+    let mut delivered = Vec::new();
+    for event in &events {
+        event.deliver(irc.clone(), &mut Sink::new(|_: &Arc<Irc>, event: Event| delivered.push(event)));
+    }
+    assert_eq!(delivered.len(), events.len());
+    for (event, delivered) in events.iter().zip(&delivered) {
+        assert_eq!(format!("{:?}", event), format!("{:?}", delivered));
+    }
+}
@@ -0,0 +1,556 @@
+//! mIRC formatting codes.
+//!
+//! Messages may contain control codes for bold, italic, colors, etc. This module can remove
+//! them with `strip`, parse them into styled spans with `parse`, and produce them with the
+//! helper functions such as `bold` and `color`, or from spans with `to_irc`. Spans can also be
+//! converted to ANSI terminal escapes and to HTML.
+//!
+//! ```ignore
+//! irc.privmsg("#hiirc", &format!("{} is {}", bold("hiirc"), color("great", Color::GREEN, None)));
+//! ```
+
+/// Bold toggle.
+pub const BOLD: char = '\x02';
+/// Color code, followed by the foreground and background colors.
+pub const COLOR: char = '\x03';
+/// Hex color code, followed by the foreground and background colors.
+pub const HEX_COLOR: char = '\x04';
+/// Reset all formatting.
+pub const RESET: char = '\x0f';
+/// Monospace toggle.
+pub const MONOSPACE: char = '\x11';
+/// Reverse colors toggle.
+pub const REVERSE: char = '\x16';
+/// Italic toggle.
+pub const ITALIC: char = '\x1d';
+/// Strikethrough toggle.
+pub const STRIKETHROUGH: char = '\x1e';
+/// Underline toggle.
+pub const UNDERLINE: char = '\x1f';
+
+// RGB values of the 99 colors, from 0 to 98.
+static PALETTE: [u32; 99] = [
+    0xffffff, 0x000000, 0x00007f, 0x009300, 0xff0000, 0x7f0000, 0x9c009c, 0xfc7f00,
+    0xffff00, 0x00fc00, 0x009393, 0x00ffff, 0x0000fc, 0xff00ff, 0x7f7f7f, 0xd2d2d2,
+    0x470000, 0x472100, 0x474700, 0x324700, 0x004700, 0x00472c, 0x004747, 0x002747,
+    0x000047, 0x2e0047, 0x470047, 0x47002a, 0x740000, 0x743a00, 0x747400, 0x517400,
+    0x007400, 0x007449, 0x007474, 0x004074, 0x000074, 0x4b0074, 0x740074, 0x740045,
+    0xb50000, 0xb56300, 0xb5b500, 0x7db500, 0x00b500, 0x00b571, 0x00b5b5, 0x0063b5,
+    0x0000b5, 0x7500b5, 0xb500b5, 0xb5006b, 0xff0000, 0xff8c00, 0xffff00, 0xb2ff00,
+    0x00ff00, 0x00ffa0, 0x00ffff, 0x008cff, 0x0000ff, 0xa500ff, 0xff00ff, 0xff0098,
+    0xff5959, 0xffb459, 0xffff71, 0xcfff60, 0x6fff6f, 0x65ffc9, 0x6dffff, 0x59b4ff,
+    0x5959ff, 0xc459ff, 0xff66ff, 0xff59bc, 0xff9c9c, 0xffd39c, 0xffff9c, 0xe2ff9c,
+    0x9cff9c, 0x9cffdb, 0x9cffff, 0x9cd3ff, 0x9c9cff, 0xdc9cff, 0xff9cff, 0xff94d3,
+    0x000000, 0x131313, 0x282828, 0x363636, 0x4d4d4d, 0x656565, 0x818181, 0x9f9f9f,
+    0xbcbcbc, 0xe2e2e2, 0xffffff,
+];
+
+/// A text color.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Color {
+    /// One of the 99 colors of the `\x03` code, from 0 to 98.
+    ///
+    /// 99 is the default color of the client, higher indices are sent as 99.
+    Palette(u8),
+    /// A color of the `\x04` hex code.
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    /// White.
+    pub const WHITE: Color = Color::Palette(0);
+    /// Black.
+    pub const BLACK: Color = Color::Palette(1);
+    /// Blue.
+    pub const BLUE: Color = Color::Palette(2);
+    /// Green.
+    pub const GREEN: Color = Color::Palette(3);
+    /// Red.
+    pub const RED: Color = Color::Palette(4);
+    /// Brown.
+    pub const BROWN: Color = Color::Palette(5);
+    /// Magenta.
+    pub const MAGENTA: Color = Color::Palette(6);
+    /// Orange.
+    pub const ORANGE: Color = Color::Palette(7);
+    /// Yellow.
+    pub const YELLOW: Color = Color::Palette(8);
+    /// Light green.
+    pub const LIGHT_GREEN: Color = Color::Palette(9);
+    /// Cyan.
+    pub const CYAN: Color = Color::Palette(10);
+    /// Light cyan.
+    pub const LIGHT_CYAN: Color = Color::Palette(11);
+    /// Light blue.
+    pub const LIGHT_BLUE: Color = Color::Palette(12);
+    /// Pink.
+    pub const PINK: Color = Color::Palette(13);
+    /// Grey.
+    pub const GREY: Color = Color::Palette(14);
+    /// Light grey.
+    pub const LIGHT_GREY: Color = Color::Palette(15);
+
+    /// Get the red, green and blue components of the color.
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        match *self {
+            Color::Palette(idx) => {
+                let value = PALETTE[(idx as usize).min(PALETTE.len() - 1)];
+                ((value >> 16) as u8, (value >> 8) as u8, value as u8)
+            }
+            Color::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+
+    fn to_rgb(self) -> Color {
+        let (r, g, b) = self.rgb();
+        Color::Rgb(r, g, b)
+    }
+
+    // The closest color of the palette.
+    fn to_palette(self) -> Color {
+        let (r, g, b) = self.rgb();
+        let distance = |value: u32| {
+            let d = |a: u8, shift: u32| (a as i32 - (value >> shift & 0xff) as i32).pow(2);
+            d(r, 16) + d(g, 8) + d(b, 0)
+        };
+        match self {
+            Color::Palette(_) => self,
+            Color::Rgb(..) => {
+                let idx = (0..PALETTE.len()).min_by_key(|&i| distance(PALETTE[i])).unwrap_or(0);
+                Color::Palette(idx as u8)
+            }
+        }
+    }
+
+    fn html(&self) -> String {
+        let (r, g, b) = self.rgb();
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+
+    fn irc(&self) -> String {
+        match *self {
+            Color::Palette(idx) => format!("{:02}", idx.min(99)),
+            Color::Rgb(r, g, b) => format!("{:02X}{:02X}{:02X}", r, g, b),
+        }
+    }
+}
+
+/// Style of a span of text.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Style {
+    /// Bold text.
+    pub bold: bool,
+    /// Italic text.
+    pub italic: bool,
+    /// Underlined text.
+    pub underline: bool,
+    /// Struck through text.
+    pub strikethrough: bool,
+    /// Monospace text.
+    pub monospace: bool,
+    /// Foreground and background colors are swapped.
+    pub reverse: bool,
+    /// Foreground color.
+    pub fg: Option<Color>,
+    /// Background color.
+    pub bg: Option<Color>,
+}
+
+impl Style {
+
+    /// Check if the text has no formatting.
+    pub fn is_plain(&self) -> bool {
+        *self == Style::default()
+    }
+
+}
+
+/// A span of text with the same style.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Span {
+    /// The text, without formatting codes.
+    pub text: String,
+    /// The style of the text.
+    pub style: Style,
+}
+
+// Read up to two decimal digits at the start of the string.
+fn read_palette(text: &str) -> Option<(Color, usize)> {
+    let len = text.bytes().take(2).take_while(|b| b.is_ascii_digit()).count();
+    if len == 0 {
+        return None;
+    }
+    text[..len].parse().ok().map(|idx| (Color::Palette(idx), len))
+}
+
+// Read six hexadecimal digits at the start of the string.
+fn read_hex(text: &str) -> Option<(Color, usize)> {
+    if text.len() < 6 || !text.bytes().take(6).all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let value = u32::from_str_radix(&text[..6], 16).ok()?;
+    Some((Color::Rgb((value >> 16) as u8, (value >> 8) as u8, value as u8), 6))
+}
+
+// Read the colors following a color code, returning the colors and the length read.
+fn read_colors<F>(text: &str, read: F) -> (Option<Color>, Option<Color>, usize)
+    where F: Fn(&str) -> Option<(Color, usize)>
+{
+    let (fg, len) = match read(text) {
+        Some(res) => res,
+        None => return (None, None, 0),
+    };
+
+    if text[len..].starts_with(',') {
+        if let Some((bg, bg_len)) = read(&text[len + 1..]) {
+            return (Some(fg), Some(bg), len + 1 + bg_len);
+        }
+    }
+
+    (Some(fg), None, len)
+}
+
+// Color 99 means the default color.
+fn palette_default(color: Option<Color>) -> Option<Color> {
+    match color {
+        Some(Color::Palette(99)) => None,
+        color => color,
+    }
+}
+
+/// Parse the text into spans of styled text.
+pub fn parse(text: &str) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut style = Style::default();
+    let mut current = String::new();
+    let mut idx = 0;
+
+    while let Some(c) = text[idx..].chars().next() {
+        idx += c.len_utf8();
+
+        let mut next = style;
+        match c {
+            BOLD => next.bold = !next.bold,
+            ITALIC => next.italic = !next.italic,
+            UNDERLINE => next.underline = !next.underline,
+            STRIKETHROUGH => next.strikethrough = !next.strikethrough,
+            MONOSPACE => next.monospace = !next.monospace,
+            REVERSE => next.reverse = !next.reverse,
+            RESET => next = Style::default(),
+            COLOR | HEX_COLOR => {
+                let (fg, bg, len) = if c == COLOR {
+                    read_colors(&text[idx..], read_palette)
+                } else {
+                    read_colors(&text[idx..], read_hex)
+                };
+                idx += len;
+                if fg.is_none() {
+                    next.fg = None;
+                    next.bg = None;
+                } else {
+                    next.fg = palette_default(fg);
+                    if bg.is_some() {
+                        next.bg = palette_default(bg);
+                    }
+                }
+            }
+            c => {
+                current.push(c);
+                continue;
+            }
+        }
+
+        if next != style {
+            if !current.is_empty() {
+                spans.push(Span {
+                    text: current,
+                    style,
+                });
+                current = String::new();
+            }
+            style = next;
+        }
+    }
+
+    if !current.is_empty() {
+        spans.push(Span {
+            text: current,
+            style,
+        });
+    }
+
+    spans
+}
+
+/// Remove the formatting codes from the text.
+pub fn strip(text: &str) -> String {
+    parse(text).into_iter().map(|span| span.text).collect()
+}
+
+// Codes to go from the default style to the given style.
+fn style_codes(style: &Style, text: &str) -> String {
+    let mut codes = String::new();
+    if style.bold {
+        codes.push(BOLD);
+    }
+    if style.italic {
+        codes.push(ITALIC);
+    }
+    if style.underline {
+        codes.push(UNDERLINE);
+    }
+    if style.strikethrough {
+        codes.push(STRIKETHROUGH);
+    }
+    if style.monospace {
+        codes.push(MONOSPACE);
+    }
+    if style.reverse {
+        codes.push(REVERSE);
+    }
+
+    if let (None, Some(bg)) = (style.fg, style.bg) {
+        // The default foreground, 99, only exists in the palette, so the background is sent
+        // as the closest color of the palette.
+        codes.push(COLOR);
+        codes.push_str("99,");
+        codes.push_str(&bg.to_palette().irc());
+    } else if let Some(fg) = style.fg {
+        let hex = matches!((fg, style.bg), (Color::Rgb(..), _) | (_, Some(Color::Rgb(..))));
+        codes.push(if hex { HEX_COLOR } else { COLOR });
+        codes.push_str(&if hex { fg.to_rgb() } else { fg }.irc());
+        if let Some(bg) = style.bg {
+            codes.push(',');
+            codes.push_str(&if hex { bg.to_rgb() } else { bg }.irc());
+        } else if text.starts_with(',') {
+            // Keep a leading comma from being read as the background color.
+            codes.push(BOLD);
+            codes.push(BOLD);
+        }
+    }
+    codes
+}
+
+/// Build a message with formatting codes from spans.
+pub fn to_irc(spans: &[Span]) -> String {
+    let mut out = String::new();
+    let mut style = Style::default();
+
+    for span in spans {
+        if span.style != style {
+            if !style.is_plain() {
+                out.push(RESET);
+            }
+            out.push_str(&style_codes(&span.style, &span.text));
+            style = span.style;
+        }
+        out.push_str(&span.text);
+    }
+
+    if !style.is_plain() {
+        out.push(RESET);
+    }
+
+    out
+}
+
+fn styled(text: &str, style: Style) -> String {
+    to_irc(&[Span {
+        text: text.into(),
+        style,
+    }])
+}
+
+/// Make the text bold.
+pub fn bold(text: &str) -> String {
+    styled(text, Style { bold: true, ..Style::default() })
+}
+
+/// Make the text italic.
+pub fn italic(text: &str) -> String {
+    styled(text, Style { italic: true, ..Style::default() })
+}
+
+/// Underline the text.
+pub fn underline(text: &str) -> String {
+    styled(text, Style { underline: true, ..Style::default() })
+}
+
+/// Strike through the text.
+pub fn strikethrough(text: &str) -> String {
+    styled(text, Style { strikethrough: true, ..Style::default() })
+}
+
+/// Make the text monospace.
+pub fn monospace(text: &str) -> String {
+    styled(text, Style { monospace: true, ..Style::default() })
+}
+
+/// Color the text, with an optional background color.
+pub fn color(text: &str, fg: Color, bg: Option<Color>) -> String {
+    styled(text, Style { fg: Some(fg), bg, ..Style::default() })
+}
+
+/// Convert spans to text with ANSI terminal escapes.
+pub fn to_ansi(spans: &[Span]) -> String {
+    let mut out = String::new();
+
+    for span in spans {
+        let style = &span.style;
+        if style.is_plain() {
+            out.push_str(&span.text);
+            continue;
+        }
+
+        let mut codes = Vec::new();
+        if style.bold {
+            codes.push("1".to_string());
+        }
+        if style.italic {
+            codes.push("3".to_string());
+        }
+        if style.underline {
+            codes.push("4".to_string());
+        }
+        if style.reverse {
+            codes.push("7".to_string());
+        }
+        if style.strikethrough {
+            codes.push("9".to_string());
+        }
+        if let Some(fg) = style.fg {
+            let (r, g, b) = fg.rgb();
+            codes.push(format!("38;2;{};{};{}", r, g, b));
+        }
+        if let Some(bg) = style.bg {
+            let (r, g, b) = bg.rgb();
+            codes.push(format!("48;2;{};{};{}", r, g, b));
+        }
+
+        if codes.is_empty() {
+            out.push_str(&span.text);
+        } else {
+            out.push_str(&format!("\x1b[{}m{}\x1b[0m", codes.join(";"), span.text));
+        }
+    }
+
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Convert spans to HTML, with inline styles.
+///
+/// The text is escaped.
+pub fn to_html(spans: &[Span]) -> String {
+    let mut out = String::new();
+
+    for span in spans {
+        let style = &span.style;
+        if style.is_plain() {
+            out.push_str(&escape_html(&span.text));
+            continue;
+        }
+
+        let mut css = Vec::new();
+        if style.bold {
+            css.push("font-weight:bold".to_string());
+        }
+        if style.italic {
+            css.push("font-style:italic".to_string());
+        }
+        match (style.underline, style.strikethrough) {
+            (true, true) => css.push("text-decoration:underline line-through".to_string()),
+            (true, false) => css.push("text-decoration:underline".to_string()),
+            (false, true) => css.push("text-decoration:line-through".to_string()),
+            (false, false) => {}
+        }
+        if style.monospace {
+            css.push("font-family:monospace".to_string());
+        }
+
+        let (fg, bg) = if style.reverse {
+            (Some(style.bg.unwrap_or(Color::WHITE)), Some(style.fg.unwrap_or(Color::BLACK)))
+        } else {
+            (style.fg, style.bg)
+        };
+        if let Some(fg) = fg {
+            css.push(format!("color:{}", fg.html()));
+        }
+        if let Some(bg) = bg {
+            css.push(format!("background-color:{}", bg.html()));
+        }
+
+        out.push_str(&format!("<span style=\"{}\">{}</span>", css.join(";"), escape_html(&span.text)));
+    }
+
+    out
+}
+
+#[test]
+fn test_strip() {
+    assert_eq!(strip("\x02bold\x02 \x034,12colored\x03 \x1ditalic\x0f \x04ff0000,00ff00hex\x04"),
+               "bold colored italic hex");
+    assert_eq!(strip("\x0312,3"), "");
+    assert_eq!(strip("\x03,5text"), ",5text");
+    assert_eq!(strip("\x031234"), "34");
+}
+
+#[test]
+fn test_parse() {
+    let spans = parse("a\x02b\x034,1c\x1fd\x0fe");
+    assert_eq!(spans.len(), 5);
+    assert_eq!(spans[0].text, "a");
+    assert!(spans[0].style.is_plain());
+    assert!(spans[1].style.bold);
+    assert_eq!(spans[2].style.fg, Some(Color::RED));
+    assert_eq!(spans[2].style.bg, Some(Color::BLACK));
+    assert!(spans[3].style.underline && spans[3].style.bold);
+    assert!(spans[4].style.is_plain());
+
+    let spans = parse("\x04FF8000text");
+    assert_eq!(spans[0].style.fg, Some(Color::Rgb(0xff, 0x80, 0x00)));
+}
+
+#[test]
+fn test_build_round_trip() {
+    let text = format!("{} {} {}", bold("a"), color(",x", Color::GREEN, None), color("b", Color::RED, Some(Color::WHITE)));
+    assert_eq!(text, "\x02a\x0f \x0303\x02\x02,x\x0f \x0304,00b\x0f");
+    assert_eq!(strip(&text), "a ,x b");
+    assert_eq!(to_irc(&parse(&text)), text);
+}
+
+#[test]
+fn test_build_colors() {
+    let style = |fg, bg| Span { text: "x".into(), style: Style { fg, bg, ..Style::default() } };
+    assert_eq!(to_irc(&[style(None, Some(Color::RED))]), "\x0399,04x\x0f");
+    assert_eq!(to_irc(&[style(None, Some(Color::Rgb(0xfe, 0x01, 0x02)))]), "\x0399,04x\x0f");
+    assert_eq!(to_irc(&[style(Some(Color::Rgb(1, 2, 3)), Some(Color::WHITE))]), "\x04010203,FFFFFFx\x0f");
+    assert_eq!(to_irc(&[style(Some(Color::Palette(150)), None)]), "\x0399x\x0f");
+    assert_eq!(parse("\x0399,04x")[0].style, Style { bg: Some(Color::RED), ..Style::default() });
+}
+
+#[test]
+fn test_to_html() {
+    let spans = parse("<\x02b\x02 \x034r");
+    assert_eq!(to_html(&spans),
+               "&lt;<span style=\"font-weight:bold\">b</span> <span style=\"color:#ff0000\">r</span>");
+}
+
+#[test]
+fn test_to_ansi() {
+    assert_eq!(to_ansi(&parse("a\x02b")), "a\x1b[1mb\x1b[0m");
+}
@@ -0,0 +1,337 @@
+//! Hostmasks of the form `nick!user@host`, with wildcards.
+//!
+//! A `Hostmask` is used for ban lists, ignore lists and permission rules. In a mask, `*`
+//! matches any sequence of characters and `?` matches a single character. Masks are compared
+//! using the case mapping of the server, which is available with `Irc::casemapping`.
+//! Extended bans like `$a:account` are kept as they are and never match a user.
+//!
+//! ```ignore
+//! let mask = Hostmask::ban_mask(&user, BanStyle::Host);
+//! assert!(mask.matches_user(&user, irc.casemapping()));
+//! irc.command("MODE", &[channel, "+b", &mask.to_string()], None)?;
+//! ```
+
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use isupport::CaseMapping;
+use loirc::PrefixUser;
+
+/// A `nick!user@host` mask, or an extended ban.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Hostmask {
+    /// A `nick!user@host` mask.
+    Mask {
+        /// Nickname part of the mask.
+        nickname: String,
+        /// Username part of the mask.
+        username: String,
+        /// Hostname part of the mask.
+        hostname: String,
+    },
+    /// An extended ban such as `$a:account` or `~q:nick!*@*`, kept as it was written.
+    ///
+    /// Its meaning depends on the server, so it never matches a user.
+    Extban(String),
+}
+
+/// The styles of ban masks which can be generated from a user.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BanStyle {
+    /// `nick!user@host`, matches only this exact user.
+    Full,
+    /// `nick!*@*`, matches the nickname.
+    Nickname,
+    /// `*!user@host`, matches the username on this host.
+    UserHost,
+    /// `*!*@host`, matches anyone on this host.
+    Host,
+    /// `*!user@*.domain`, matches the username on any host of the domain.
+    ///
+    /// For IPv4 addresses, the last number is replaced instead, like `*!user@192.0.2.*`.
+    UserDomain,
+    /// `*!*@*.domain`, matches anyone on any host of the domain.
+    Domain,
+}
+
+impl Hostmask {
+
+    /// Create a mask from its parts.
+    pub fn new(nickname: &str, username: &str, hostname: &str) -> Hostmask {
+        Hostmask::Mask {
+            nickname: nickname.into(),
+            username: username.into(),
+            hostname: hostname.into(),
+        }
+    }
+
+    /// Create a mask matching exactly the given user.
+    pub fn from_user(user: &PrefixUser) -> Hostmask {
+        Hostmask::new(&user.nickname, &user.username, &user.hostname)
+    }
+
+    /// Parse a mask.
+    ///
+    /// Missing parts are filled with `*` the way servers do it, so `nick` becomes `nick!*@*`,
+    /// `user@host` becomes `*!user@host` and `host.name` becomes `*!*@host.name`. Extended
+    /// bans, starting with `$` or with `~` and a letter followed by `:`, are kept as they are.
+    pub fn parse(mask: &str) -> Hostmask {
+        if is_extban(mask) {
+            return Hostmask::Extban(mask.into());
+        }
+        let (rest, hostname) = match mask.find('@') {
+            Some(idx) => (&mask[..idx], &mask[idx + 1..]),
+            None if mask.contains('.') && !mask.contains('!') => ("*", mask),
+            None => (mask, "*"),
+        };
+        let (nickname, username) = match rest.find('!') {
+            Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+            None if mask.contains('@') => ("*", rest),
+            None => (rest, "*"),
+        };
+
+        Hostmask::new(or_any(nickname), or_any(username), or_any(hostname))
+    }
+
+    /// Generate a ban mask for the given user.
+    ///
+    /// A `~` in front of the username, which servers add when ident is not available,
+    /// is replaced by `*` since it can change between connections.
+    pub fn ban_mask(user: &PrefixUser, style: BanStyle) -> Hostmask {
+        let username = match user.username.strip_prefix('~') {
+            Some(username) => format!("*{}", username),
+            None => user.username.clone(),
+        };
+
+        match style {
+            BanStyle::Full => Hostmask::new(&user.nickname, &username, &user.hostname),
+            BanStyle::Nickname => Hostmask::new(&user.nickname, "*", "*"),
+            BanStyle::UserHost => Hostmask::new("*", &username, &user.hostname),
+            BanStyle::Host => Hostmask::new("*", "*", &user.hostname),
+            BanStyle::UserDomain => Hostmask::new("*", &username, &domain_mask(&user.hostname)),
+            BanStyle::Domain => Hostmask::new("*", "*", &domain_mask(&user.hostname)),
+        }
+    }
+
+    /// Get the nickname part of the mask, empty for an extended ban.
+    pub fn nickname(&self) -> &str {
+        match *self {
+            Hostmask::Mask { ref nickname, .. } => nickname,
+            Hostmask::Extban(_) => "",
+        }
+    }
+
+    /// Get the username part of the mask, empty for an extended ban.
+    pub fn username(&self) -> &str {
+        match *self {
+            Hostmask::Mask { ref username, .. } => username,
+            Hostmask::Extban(_) => "",
+        }
+    }
+
+    /// Get the hostname part of the mask, empty for an extended ban.
+    pub fn hostname(&self) -> &str {
+        match *self {
+            Hostmask::Mask { ref hostname, .. } => hostname,
+            Hostmask::Extban(_) => "",
+        }
+    }
+
+    /// Check if this is an extended ban.
+    pub fn is_extban(&self) -> bool {
+        matches!(*self, Hostmask::Extban(_))
+    }
+
+    /// Check if the mask contains any wildcard.
+    pub fn has_wildcards(&self) -> bool {
+        match *self {
+            Hostmask::Mask { ref nickname, ref username, ref hostname } => {
+                [nickname, username, hostname].iter().any(|p| p.contains('*') || p.contains('?'))
+            }
+            Hostmask::Extban(ref extban) => extban.contains('*') || extban.contains('?'),
+        }
+    }
+
+    /// Check if the mask matches the given user.
+    ///
+    /// An extended ban never matches.
+    pub fn matches_user(&self, user: &PrefixUser, casemapping: CaseMapping) -> bool {
+        self.matches_parts(&user.nickname, &user.username, &user.hostname, casemapping)
+    }
+
+    /// Check if the mask matches the given nickname, username and hostname.
+    ///
+    /// An extended ban never matches.
+    pub fn matches_parts(&self, nickname: &str, username: &str, hostname: &str, casemapping: CaseMapping) -> bool {
+        match *self {
+            Hostmask::Mask { nickname: ref n, username: ref u, hostname: ref h } => {
+                wildcard_match(n, nickname, casemapping)
+                    && wildcard_match(u, username, casemapping)
+                    && wildcard_match(h, hostname, casemapping)
+            }
+            Hostmask::Extban(_) => false,
+        }
+    }
+
+    /// Check if this mask matches everything the other mask matches.
+    ///
+    /// This is a textual check, it is used to tell if a ban covers another one. An extended
+    /// ban only covers the same extended ban.
+    pub fn covers(&self, other: &Hostmask, casemapping: CaseMapping) -> bool {
+        match *other {
+            Hostmask::Mask { ref nickname, ref username, ref hostname } => {
+                self.matches_parts(nickname, username, hostname, casemapping)
+            }
+            Hostmask::Extban(_) => self.eq_mapped(other, casemapping),
+        }
+    }
+
+    /// Check if two masks are the same, using the case mapping.
+    pub fn eq_mapped(&self, other: &Hostmask, casemapping: CaseMapping) -> bool {
+        match (self, other) {
+            (Hostmask::Extban(a), Hostmask::Extban(b)) => casemapping.eq(a, b),
+            (Hostmask::Mask { .. }, Hostmask::Mask { .. }) => {
+                casemapping.eq(self.nickname(), other.nickname())
+                    && casemapping.eq(self.username(), other.username())
+                    && casemapping.eq(self.hostname(), other.hostname())
+            }
+            _ => false,
+        }
+    }
+
+}
+
+impl Display for Hostmask {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Hostmask::Mask { ref nickname, ref username, ref hostname } => {
+                write!(f, "{}!{}@{}", nickname, username, hostname)
+            }
+            Hostmask::Extban(ref extban) => f.write_str(extban),
+        }
+    }
+}
+
+impl FromStr for Hostmask {
+    type Err = ();
+
+    fn from_str(mask: &str) -> Result<Hostmask, ()> {
+        Ok(Hostmask::parse(mask))
+    }
+}
+
+// Extended bans look like `$a:account`, `$~a` or `~q:nick!*@*`.
+fn is_extban(mask: &str) -> bool {
+    let mut chars = mask.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('$'), _, _) => true,
+        (Some('~'), Some(c), Some(':')) => c.is_ascii_alphabetic(),
+        _ => false,
+    }
+}
+
+fn or_any(part: &str) -> &str {
+    if part.is_empty() { "*" } else { part }
+}
+
+// Replace the most specific part of a hostname with a wildcard.
+fn domain_mask(hostname: &str) -> String {
+    match hostname.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => match hostname.rfind('.') {
+            Some(idx) => format!("{}.*", &hostname[..idx]),
+            None => hostname.into(),
+        },
+        Ok(IpAddr::V6(_)) => hostname.into(),
+        Err(_) => match hostname.find('.') {
+            Some(idx) if hostname[idx + 1..].contains('.') => format!("*{}", &hostname[idx..]),
+            _ => hostname.into(),
+        },
+    }
+}
+
+// Match a pattern with `*` and `?` wildcards, using the case mapping.
+pub(crate) fn wildcard_match(pattern: &str, text: &str, casemapping: CaseMapping) -> bool {
+    let pattern: Vec<char> = pattern.chars().map(|c| casemapping.to_lower_char(c)).collect();
+    let text: Vec<char> = text.chars().map(|c| casemapping.to_lower_char(c)).collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[test]
+fn test_wildcard_match() {
+    let cm = CaseMapping::Rfc1459;
+    assert!(wildcard_match("*.example.com", "host.example.com", cm));
+    assert!(wildcard_match("BOB[1]", "bob{1}", cm));
+    assert!(!wildcard_match("BOB[1]", "bob{1}", CaseMapping::Ascii));
+    assert!(wildcard_match("b?b*", "bob", cm));
+    assert!(!wildcard_match("example.com", "host.example.com", cm));
+    assert!(!wildcard_match("b?b", "bb", cm));
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(Hostmask::parse("nick!user@host").to_string(), "nick!user@host");
+    assert_eq!(Hostmask::parse("nick").to_string(), "nick!*@*");
+    assert_eq!(Hostmask::parse("user@host").to_string(), "*!user@host");
+    assert_eq!(Hostmask::parse("host.example.com").to_string(), "*!*@host.example.com");
+    assert_eq!(Hostmask::parse("nick!@").to_string(), "nick!*@*");
+    assert_eq!(Hostmask::parse("~nick").to_string(), "~nick!*@*");
+}
+
+#[test]
+fn test_extbans() {
+    let cm = CaseMapping::Rfc1459;
+    let user = PrefixUser { nickname: "bob".into(), username: "b".into(), hostname: "host".into() };
+    for extban in ["$a:account", "$~a", "~q:bob!*@*", "~a:bob"] {
+        let mask = Hostmask::parse(extban);
+        assert_eq!(mask, Hostmask::Extban(extban.into()));
+        assert_eq!(mask.to_string(), extban);
+        assert!(!mask.matches_user(&user, cm));
+    }
+    let any = Hostmask::parse("*!*@*");
+    assert!(!any.covers(&Hostmask::parse("$a:account"), cm));
+    assert!(Hostmask::parse("$a:Account").eq_mapped(&Hostmask::parse("$a:account"), cm));
+    assert!(!any.eq_mapped(&Hostmask::parse("$a:account"), cm));
+}
+
+#[test]
+fn test_ban_mask() {
+    let user = PrefixUser {
+        nickname: "bob".into(),
+        username: "~bob".into(),
+        hostname: "host-1.isp.example.com".into(),
+    };
+    let mask = |style| Hostmask::ban_mask(&user, style).to_string();
+    assert_eq!(mask(BanStyle::Host), "*!*@host-1.isp.example.com");
+    assert_eq!(mask(BanStyle::UserHost), "*!*bob@host-1.isp.example.com");
+    assert_eq!(mask(BanStyle::UserDomain), "*!*bob@*.isp.example.com");
+    assert_eq!(mask(BanStyle::Nickname), "bob!*@*");
+
+    let ip = PrefixUser { hostname: "192.0.2.7".into(), ..user.clone() };
+    assert_eq!(Hostmask::ban_mask(&ip, BanStyle::Domain).to_string(), "*!*@192.0.2.*");
+
+    for style in [BanStyle::Full, BanStyle::Host, BanStyle::UserDomain, BanStyle::Domain] {
+        assert!(Hostmask::ban_mask(&user, style).matches_user(&user, CaseMapping::Rfc1459));
+    }
+    assert!(Hostmask::parse("*!*@*.example.com").covers(&Hostmask::parse("*!*@*.isp.example.com"), CaseMapping::Rfc1459));
+}
@@ -0,0 +1,295 @@
+//! Features advertised by the server with the ISUPPORT (005) reply.
+
+use std::collections::HashMap;
+
+/// Rules used by the server to compare nicknames and channel names without case.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CaseMapping {
+    /// Only the letters `A` to `Z` have a lowercase version.
+    Ascii,
+    /// Like `Ascii`, and `[]\^` are the uppercase versions of `{}|~`.
+    ///
+    /// This is the default.
+    #[default]
+    Rfc1459,
+    /// Like `Ascii`, and `[]\` are the uppercase versions of `{}|`.
+    StrictRfc1459,
+}
+
+impl CaseMapping {
+
+    /// Get the lowercase version of a character.
+    pub fn to_lower_char(&self, c: char) -> char {
+        match (*self, c) {
+            (_, 'A'..='Z') => c.to_ascii_lowercase(),
+            (CaseMapping::Rfc1459, '[') | (CaseMapping::StrictRfc1459, '[') => '{',
+            (CaseMapping::Rfc1459, ']') | (CaseMapping::StrictRfc1459, ']') => '}',
+            (CaseMapping::Rfc1459, '\\') | (CaseMapping::StrictRfc1459, '\\') => '|',
+            (CaseMapping::Rfc1459, '^') => '~',
+            _ => c,
+        }
+    }
+
+    /// Get the lowercase version of a nickname or channel name.
+    ///
+    /// Names are stored and looked up by their normalized version, so that two names which
+    /// only differ by case refer to the same user or channel.
+    pub fn normalize(&self, text: &str) -> String {
+        text.chars().map(|c| self.to_lower_char(c)).collect()
+    }
+
+    /// Check if two strings are equal without case.
+    pub fn eq(&self, a: &str, b: &str) -> bool {
+        a.chars().count() == b.chars().count()
+            && a.chars().zip(b.chars()).all(|(a, b)| self.to_lower_char(a) == self.to_lower_char(b))
+    }
+
+}
+
+/// A single change in a MODE message, such as `+o nick`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ModeChange {
+    /// True if the mode is set, false if it's unset.
+    pub set: bool,
+    /// The mode character.
+    pub mode: char,
+    /// The parameter of the mode, if it takes one.
+    pub param: Option<String>,
+}
+
+/// Tokens of the ISUPPORT replies sent by the server.
+#[derive(Clone, Debug, Default)]
+pub struct ISupport {
+    tokens: HashMap<String, String>,
+}
+
+impl ISupport {
+
+    /// Get the value of a token.
+    ///
+    /// Tokens without a value have an empty value.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.tokens.get(name).map(|v| &v[..])
+    }
+
+    /// Check if the server advertised a token.
+    pub fn contains(&self, name: &str) -> bool {
+        self.tokens.contains_key(name)
+    }
+
+    /// Get the case mapping, `CASEMAPPING`.
+    pub fn casemapping(&self) -> CaseMapping {
+        match self.get("CASEMAPPING") {
+            Some("ascii") => CaseMapping::Ascii,
+            Some("strict-rfc1459") => CaseMapping::StrictRfc1459,
+            _ => CaseMapping::Rfc1459,
+        }
+    }
+
+    /// Get the characters channel names can start with, `CHANTYPES`.
+    pub fn chantypes(&self) -> &str {
+        self.get("CHANTYPES").unwrap_or("#&")
+    }
+
+    /// Check if a name starts with one of the channel types.
+    pub fn is_channel(&self, name: &str) -> bool {
+        name.chars().next().map(|c| self.chantypes().contains(c)).unwrap_or(false)
+    }
+
+    /// Get the maximum length of nicknames, `NICKLEN`, if advertised.
+    pub fn nicklen(&self) -> Option<usize> {
+        self.get("NICKLEN").and_then(|v| v.parse().ok())
+    }
+
+    /// Get the maximum length of channel names, `CHANNELLEN`, if there's one.
+    ///
+    /// Servers which don't advertise it use a limit of 200.
+    pub fn channellen(&self) -> Option<usize> {
+        match self.get("CHANNELLEN") {
+            Some(value) => value.parse().ok(),
+            None => Some(200),
+        }
+    }
+
+    /// Get the maximum number of targets of a command, `TARGMAX`, if there's one.
+    pub fn max_targets(&self, command: &str) -> Option<usize> {
+        let value = self.get("TARGMAX")?;
+        value.split(',')
+            .filter_map(|t| t.split_once(':'))
+            .find(|&(name, _)| name.eq_ignore_ascii_case(command))
+            .and_then(|(_, max)| max.parse().ok())
+    }
+
+    /// Get the channel modes giving a status to users, with their prefix, `PREFIX`.
+    ///
+    /// They are ordered from the highest status to the lowest, like `[('o', '@'), ('v', '+')]`.
+    pub fn prefix(&self) -> Vec<(char, char)> {
+        let value = self.get("PREFIX").unwrap_or("(qaohv)~&@%+");
+        if let Some(value) = value.strip_prefix('(') {
+            if let Some(end) = value.find(')') {
+                return value[..end].chars().zip(value[end + 1..].chars()).collect();
+            }
+        }
+        Vec::new()
+    }
+
+    /// Get the maximum number of modes with a parameter in a single MODE, `MODES`, if there's one.
+    ///
+    /// Servers which don't advertise it accept 3 modes.
+    pub fn max_modes(&self) -> Option<usize> {
+        match self.get("MODES") {
+            Some(value) => value.parse().ok(),
+            None => Some(3),
+        }
+    }
+
+    /// Get the mode used to quiet users and the prefix of its masks, if the server has one.
+    ///
+    /// Servers have either a `q` list mode, or a `q` extended ban of the `b` mode advertised
+    /// by `EXTBAN`, such as `~q:`.
+    pub fn quiet_mode(&self) -> Option<(char, String)> {
+        let is_status = self.prefix().iter().any(|&(mode, _)| mode == 'q');
+        if !is_status && self.chanmodes()[0].contains('q') {
+            return Some(('q', String::new()));
+        }
+        let (prefix, types) = self.get("EXTBAN")?.split_once(',')?;
+        if types.contains('q') {
+            Some(('b', format!("{}q:", prefix)))
+        } else {
+            None
+        }
+    }
+
+    /// Get the channel mode of the invite list, `INVEX`, if the server has one.
+    ///
+    /// Servers which don't advertise it but have an `I` list mode use `I`.
+    pub fn invex(&self) -> Option<char> {
+        match self.get("INVEX") {
+            Some(value) => value.chars().next().or(Some('I')),
+            None if self.chanmodes()[0].contains('I') => Some('I'),
+            None => None,
+        }
+    }
+
+    /// Get the four types of channel modes, `CHANMODES`.
+    ///
+    /// They are the list modes, the modes which always take a parameter, the modes which
+    /// take a parameter only when set, and the modes which never take a parameter.
+    pub fn chanmodes(&self) -> [String; 4] {
+        let value = self.get("CHANMODES").unwrap_or("beI,k,l,imnpst");
+        let mut types = value.split(',');
+        let mut next = || types.next().unwrap_or("").to_string();
+        [next(), next(), next(), next()]
+    }
+
+    /// Split a mode string and its parameters into single changes.
+    ///
+    /// The parameters are matched to the modes using `PREFIX` and `CHANMODES`.
+    pub fn parse_modes<S: AsRef<str>>(&self, modes: &str, params: &[S]) -> Vec<ModeChange> {
+        let prefix = self.prefix();
+        let chanmodes = self.chanmodes();
+        let mut params = params.iter();
+        let mut changes = Vec::new();
+        let mut set = true;
+
+        for mode in modes.chars() {
+            match mode {
+                '+' => set = true,
+                '-' => set = false,
+                _ => {
+                    let takes_param = prefix.iter().any(|&(m, _)| m == mode)
+                        || chanmodes[0].contains(mode)
+                        || chanmodes[1].contains(mode)
+                        || (set && chanmodes[2].contains(mode));
+                    let param = if takes_param {
+                        params.next().map(|p| p.as_ref().to_string())
+                    } else {
+                        None
+                    };
+                    changes.push(ModeChange {
+                        set,
+                        mode,
+                        param,
+                    });
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// Add the tokens of an ISUPPORT reply.
+    ///
+    /// The arguments are the ones of the reply, without our nickname and the trailing text.
+    pub(crate) fn update<S: AsRef<str>>(&mut self, args: &[S]) {
+        for arg in args {
+            let arg = arg.as_ref();
+            if let Some(name) = arg.strip_prefix('-') {
+                self.tokens.remove(name);
+            } else {
+                match arg.find('=') {
+                    Some(idx) => self.tokens.insert(arg[..idx].into(), arg[idx + 1..].into()),
+                    None => self.tokens.insert(arg.into(), String::new()),
+                };
+            }
+        }
+    }
+
+}
+
+#[test]
+fn test_casemapping() {
+    assert!(CaseMapping::Rfc1459.eq("Nick[a]^", "nick{a}~"));
+    assert!(CaseMapping::Rfc1459.eq("a^", "a~"));
+    assert!(!CaseMapping::StrictRfc1459.eq("a^", "a~"));
+    assert_eq!(CaseMapping::Rfc1459.normalize("a~^"), "a~~");
+    assert!(!CaseMapping::Ascii.eq("nick[", "nick{"));
+    assert_eq!(CaseMapping::Rfc1459.normalize("A[\\]"), "a{|}");
+}
+
+#[test]
+fn test_isupport() {
+    let mut isupport = ISupport::default();
+    isupport.update(&["CASEMAPPING=ascii", "PREFIX=(ov)@+", "CHANMODES=beI,k,l,imnt", "EXCEPTS"]);
+    assert_eq!(isupport.casemapping(), CaseMapping::Ascii);
+    assert_eq!(isupport.prefix(), vec![('o', '@'), ('v', '+')]);
+    assert_eq!(isupport.get("EXCEPTS"), Some(""));
+
+    isupport.update(&["-EXCEPTS"]);
+    assert!(!isupport.contains("EXCEPTS"));
+
+    assert!(isupport.is_channel("&local"));
+    assert_eq!(isupport.channellen(), Some(200));
+    isupport.update(&["CHANTYPES=#", "NICKLEN=9", "CHANNELLEN="]);
+    assert!(!isupport.is_channel("&local"));
+    assert_eq!(isupport.nicklen(), Some(9));
+    assert_eq!(isupport.channellen(), None);
+
+    assert_eq!(isupport.max_modes(), Some(3));
+    assert_eq!(isupport.quiet_mode(), None);
+    isupport.update(&["EXTBAN=~,qjn", "MODES="]);
+    assert_eq!(isupport.quiet_mode(), Some(('b', "~q:".into())));
+    assert_eq!(isupport.max_modes(), None);
+    isupport.update(&["CHANMODES=beIq,k,l,imnt"]);
+    assert_eq!(isupport.quiet_mode(), Some(('q', String::new())));
+
+    assert_eq!(isupport.invex(), Some('I'));
+    isupport.update(&["CHANMODES=b,k,l,imnt"]);
+    assert_eq!(isupport.invex(), None);
+    isupport.update(&["INVEX"]);
+    assert_eq!(isupport.invex(), Some('I'));
+
+    assert_eq!(isupport.max_targets("JOIN"), None);
+    isupport.update(&["TARGMAX=JOIN:3,PRIVMSG:"]);
+    assert_eq!(isupport.max_targets("join"), Some(3));
+    assert_eq!(isupport.max_targets("PRIVMSG"), None);
+
+    let changes = isupport.parse_modes("+ob-l+kt", &["alice", "*!*@host", "secret"]);
+    assert_eq!(changes, vec![
+        ModeChange { set: true, mode: 'o', param: Some("alice".into()) },
+        ModeChange { set: true, mode: 'b', param: Some("*!*@host".into()) },
+        ModeChange { set: false, mode: 'l', param: None },
+        ModeChange { set: true, mode: 'k', param: Some("secret".into()) },
+        ModeChange { set: true, mode: 't', param: None },
+    ]);
+}
@@ -0,0 +1,156 @@
+//! Lag measurement and stall detection.
+//!
+//! When enabled with `Settings::lag`, the client sends a PING carrying a timestamp every
+//! `LagSettings::interval` once registered, and measures how long the server takes to answer.
+//! The average of the last measures is available with `Irc::lag` and given to `Listener::lag`.
+//! When the server takes longer than `LagSettings::max_lag` to answer, the connection is
+//! considered stalled and dropped, so that it's reconnected if reconnection is enabled.
+//!
+//! ```ignore
+//! let settings = Settings::new("irc.freenode.net:6667", "hiirc")
+//!     .lag(Some(LagSettings { max_lag: Some(Duration::from_secs(60)), ..LagSettings::default() }));
+//! ```
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Settings of the lag measurement.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LagSettings {
+    /// Delay between two PINGs.
+    pub interval: Duration,
+    /// Number of measures in the moving average.
+    pub samples: usize,
+    /// Lag above which the connection is dropped. If None, the connection is never dropped.
+    pub max_lag: Option<Duration>,
+}
+
+impl Default for LagSettings {
+    /// PING every 30 seconds, average 5 measures, drop the connection after 2 minutes of lag.
+    fn default() -> LagSettings {
+        LagSettings {
+            interval: Duration::from_secs(30),
+            samples: 5,
+            max_lag: Some(Duration::from_secs(120)),
+        }
+    }
+}
+
+/// Keeps track of the PINGs sent and of the last measures.
+#[derive(Debug)]
+pub(crate) struct LagMeter {
+    settings: LagSettings,
+    // Token and time of the PING waiting for its PONG.
+    pending: Option<(String, Instant)>,
+    samples: VecDeque<Duration>,
+    // Changed every time the measure starts or stops, to stop the PINGs of older connections.
+    generation: u64,
+}
+
+impl LagMeter {
+
+    pub fn new(settings: LagSettings) -> LagMeter {
+        LagMeter {
+            settings,
+            pending: None,
+            samples: VecDeque::new(),
+            generation: 0,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.settings.interval
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Start measuring once registered, returns the new generation.
+    pub fn start(&mut self) -> u64 {
+        self.generation += 1;
+        self.generation
+    }
+
+    /// Stop measuring when the connection is lost, the measures of this connection are dropped.
+    pub fn stop(&mut self) {
+        self.generation += 1;
+        self.pending = None;
+        self.samples.clear();
+    }
+
+    /// Start a new measure, returns the token to send in a PING.
+    ///
+    /// Returns `None` while the previous PING is not answered.
+    pub fn ping(&mut self, now: Instant) -> Option<String> {
+        if self.pending.is_some() {
+            return None;
+        }
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        let token = format!("LAG{}", millis);
+        self.pending = Some((token.clone(), now));
+        Some(token)
+    }
+
+    /// Handle the token of a PONG, returns the new average if it answers our PING.
+    pub fn pong(&mut self, token: &str, now: Instant) -> Option<Duration> {
+        match self.pending {
+            Some((ref pending, _)) if pending == token => {}
+            _ => return None,
+        }
+        let (_, sent) = self.pending.take()?;
+        self.samples.push_back(now.duration_since(sent));
+        while self.samples.len() > self.settings.samples.max(1) {
+            self.samples.pop_front();
+        }
+        self.lag()
+    }
+
+    /// Get the average of the last measures.
+    pub fn lag(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<Duration>() / self.samples.len() as u32)
+    }
+
+    /// Check if the lag is above the limit, or if the pending PING is waiting for too long.
+    pub fn is_stalled(&self, now: Instant) -> bool {
+        let max_lag = match self.settings.max_lag {
+            Some(max_lag) => max_lag,
+            None => return false,
+        };
+        self.lag().map(|lag| lag > max_lag).unwrap_or(false)
+            || self.pending.as_ref().map(|&(_, sent)| now.duration_since(sent) > max_lag).unwrap_or(false)
+    }
+
+}
+
+#[test]
+fn test_lag_meter() {
+    let mut meter = LagMeter::new(LagSettings {
+        interval: Duration::from_secs(30),
+        samples: 2,
+        max_lag: Some(Duration::from_secs(10)),
+    });
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+
+    let token = meter.ping(start).unwrap();
+    assert_eq!(meter.ping(at(1)), None);
+    assert_eq!(meter.pong("irc.example.com", at(2)), None);
+    assert_eq!(meter.pong(&token, at(2)), Some(Duration::from_secs(2)));
+
+    let token = meter.ping(at(30)).unwrap();
+    assert_eq!(meter.pong(&token, at(34)), Some(Duration::from_secs(3)));
+    let token = meter.ping(at(60)).unwrap();
+    assert_eq!(meter.pong(&token, at(66)), Some(Duration::from_secs(5)));
+    assert!(!meter.is_stalled(at(70)));
+
+    meter.ping(at(90)).unwrap();
+    assert!(!meter.is_stalled(at(95)));
+    assert!(meter.is_stalled(at(101)));
+
+    meter.stop();
+    assert_eq!(meter.lag(), None);
+}
@@ -6,19 +6,80 @@
 //! to the dispatch function, accompanied with an instance of the `Settings` struct configured to
 //! your needs. You can also use the `Settings` struct as a builder, calling the `dispatch` method
 //! once it is configured to your needs.
+//!
+//! Numeric replies can be handled as typed values of the `replies` module, through the
+//! `reply` callback of `Listener`.
+//!
+//! Channel lists are requested with `IrcWrite::list`, and can be filtered with the types of
+//! the `list` module.
+//!
+//! Capabilities and SASL authentication are negotiated during the registration, see the
+//! `caps` module.
+//!
+//! The `lag` module measures the round-trip time to the server and drops stalled connections.
+//!
+//! The `presence` module tells when watched nicknames come online or go offline, using
+//! MONITOR or ISON.
+//!
+//! Many listeners can be composed on one connection with the `chain` module, along with
+//! middlewares that filter or modify events before they reach the listeners.
+//!
+//! Bots can use the `commands` module to route messages such as `!help` to command handlers,
+//! and restrict who can use them with the rules of the `permissions` module.
+//!
+//! Hostmasks such as `*!*@host` are handled by the `hostmask` module, which is used for
+//! channel ban lists and permission rules. Features advertised by the server are available
+//! from `Irc::isupport`, and the `names` module uses them to validate nicknames and channel
+//! names.
+//!
+//! The `formatting` module handles the mIRC formatting codes found in messages, such as bold
+//! and colors.
+//!
+//! To connect to several networks at once and handle their events in one place, use the
+//! `MultiClient` of the `multi` module.
+//!
+//! If callbacks are not convenient, the `events` method of `Settings` returns an iterator
+//! over the high-level events of the `event` module.
+//!
+//! With the `async` feature enabled, the `async_client` module offers the same client on top
+//! of tokio, with events delivered through a `Stream`.
 
 extern crate encoding;
 extern crate loirc;
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(feature = "async")]
+extern crate tokio;
 
 #[macro_use]
 mod macros;
 mod core;
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod caps;
+pub mod chain;
+pub mod commands;
+pub mod event;
 pub mod ext;
+pub mod formatting;
+pub mod hostmask;
+pub mod isupport;
+pub mod lag;
+pub mod list;
 mod listener;
+pub mod multi;
+pub mod names;
+pub mod permissions;
+pub mod presence;
+pub mod rejoin;
+pub mod replies;
 mod settings;
+#[cfg(test)]
+mod testing;
 
-pub use core::{dispatch};
-pub use core::{Channel, ConnectionStatus, Error, Irc, IrcWrite, ChannelUser, ChannelUserStatus};
+pub use core::{dispatch, dispatch_chain};
+pub use core::{Ban, Channel, ConnectionStatus, ServerInfo, Error, Irc, IrcWrite, ChannelUser, ChannelUserStatus,
+               DisconnectKind, DisconnectReason, JoinFailure};
 pub use listener::Listener;
 pub use settings::Settings;
 pub use loirc::Error as LoircError;
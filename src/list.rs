@@ -0,0 +1,236 @@
+//! Channel lists, requested with `IrcWrite::list`.
+//!
+//! Entries are given to `Listener::list_entry` as they arrive, and the whole list is given to
+//! `Listener::list_end` once the server is done. Since some networks have tens of thousands of
+//! channels, a `ListFilter` can ask the server to filter the list, and can stop the collection
+//! after a number of entries or some time.
+//!
+//! ```ignore
+//! let filter = ListFilter::new().more_users_than(50).max_entries(100);
+//! irc.list(Some(&filter))?;
+//! ```
+
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// A channel of a channel list.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ListEntry {
+    /// Name of the channel.
+    pub channel: String,
+    /// Number of users in the channel.
+    pub users: u32,
+    /// Topic of the channel, which may start with the channel modes.
+    pub topic: String,
+}
+
+/// A complete channel list.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChannelList {
+    /// Channels of the list.
+    pub entries: Vec<ListEntry>,
+    /// False if the list was cut short by `ListFilter::max_entries`, `ListFilter::timeout`
+    /// or a disconnection.
+    pub complete: bool,
+}
+
+/// Conditions and limits of a channel list.
+///
+/// The conditions are sent to the server when it supports them, as advertised by the `ELIST`
+/// token of ISUPPORT. The conditions on the number of users are also checked on our side.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ListFilter {
+    masks: Vec<String>,
+    more_users_than: Option<u32>,
+    fewer_users_than: Option<u32>,
+    created_less_than: Option<u32>,
+    created_more_than: Option<u32>,
+    topic_less_than: Option<u32>,
+    topic_more_than: Option<u32>,
+    max_entries: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+impl ListFilter {
+
+    /// Create a filter without any condition or limit.
+    pub fn new() -> ListFilter {
+        ListFilter::default()
+    }
+
+    /// Only list channels matching this name or mask.
+    ///
+    /// Masks with wildcards require the `M` extension.
+    pub fn mask(mut self, mask: &str) -> ListFilter {
+        self.masks.push(mask.into());
+        self
+    }
+
+    /// Only list channels with more than this number of users, `U` extension.
+    pub fn more_users_than(mut self, users: u32) -> ListFilter {
+        self.more_users_than = Some(users);
+        self
+    }
+
+    /// Only list channels with fewer than this number of users, `U` extension.
+    pub fn fewer_users_than(mut self, users: u32) -> ListFilter {
+        self.fewer_users_than = Some(users);
+        self
+    }
+
+    /// Only list channels created less than this number of minutes ago, `C` extension.
+    pub fn created_less_than(mut self, minutes: u32) -> ListFilter {
+        self.created_less_than = Some(minutes);
+        self
+    }
+
+    /// Only list channels created more than this number of minutes ago, `C` extension.
+    pub fn created_more_than(mut self, minutes: u32) -> ListFilter {
+        self.created_more_than = Some(minutes);
+        self
+    }
+
+    /// Only list channels whose topic changed less than this number of minutes ago, `T` extension.
+    pub fn topic_less_than(mut self, minutes: u32) -> ListFilter {
+        self.topic_less_than = Some(minutes);
+        self
+    }
+
+    /// Only list channels whose topic changed more than this number of minutes ago, `T` extension.
+    pub fn topic_more_than(mut self, minutes: u32) -> ListFilter {
+        self.topic_more_than = Some(minutes);
+        self
+    }
+
+    /// Stop collecting the list after this number of entries.
+    pub fn max_entries(mut self, max_entries: usize) -> ListFilter {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Stop collecting the list after this duration.
+    ///
+    /// The duration is checked whenever a message is received, so the list can end a bit later.
+    pub fn timeout(mut self, timeout: Duration) -> ListFilter {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Build the parameter of the LIST command.
+    ///
+    /// With `Some(elist)`, the conditions the server doesn't support are left out.
+    pub(crate) fn param(&self, elist: Option<&str>) -> Option<String> {
+        let supports = |ext: char| elist.map(|e| e.contains(ext) || e.contains(ext.to_ascii_lowercase())).unwrap_or(true);
+        let mut conditions = Vec::new();
+
+        for mask in &self.masks {
+            if supports('M') || !mask.contains(['*', '?']) {
+                conditions.push(mask.clone());
+            }
+        }
+        if supports('U') {
+            conditions.extend(self.more_users_than.map(|n| format!(">{}", n)));
+            conditions.extend(self.fewer_users_than.map(|n| format!("<{}", n)));
+        }
+        if supports('C') {
+            conditions.extend(self.created_less_than.map(|n| format!("C<{}", n)));
+            conditions.extend(self.created_more_than.map(|n| format!("C>{}", n)));
+        }
+        if supports('T') {
+            conditions.extend(self.topic_less_than.map(|n| format!("T<{}", n)));
+            conditions.extend(self.topic_more_than.map(|n| format!("T>{}", n)));
+        }
+
+        if conditions.is_empty() {
+            None
+        } else {
+            Some(conditions.join(","))
+        }
+    }
+
+    fn accepts(&self, entry: &ListEntry) -> bool {
+        self.more_users_than.map(|n| entry.users > n).unwrap_or(true)
+            && self.fewer_users_than.map(|n| entry.users < n).unwrap_or(true)
+    }
+
+}
+
+/// A channel list being received.
+#[derive(Debug)]
+pub(crate) struct PendingList {
+    filter: ListFilter,
+    entries: Vec<ListEntry>,
+    started: Instant,
+    // Set once the list was given to the listener, the remaining entries are ignored.
+    done: bool,
+}
+
+impl PendingList {
+
+    pub fn new(filter: ListFilter) -> PendingList {
+        PendingList {
+            filter,
+            entries: Vec::new(),
+            started: Instant::now(),
+            done: false,
+        }
+    }
+
+    /// Add an entry, returns false if it's filtered out or if the list is done.
+    pub fn push(&mut self, entry: &ListEntry) -> bool {
+        if self.done || !self.filter.accepts(entry) {
+            return false;
+        }
+        self.entries.push(entry.clone());
+        true
+    }
+
+    /// Check if the list reached one of its limits.
+    pub fn is_over_limit(&self) -> bool {
+        !self.done
+            && (self.filter.max_entries.map(|n| self.entries.len() >= n).unwrap_or(false)
+                || self.filter.timeout.map(|t| self.started.elapsed() >= t).unwrap_or(false))
+    }
+
+    /// Take the collected list, the remaining entries are ignored.
+    ///
+    /// Returns `None` if the list was already taken.
+    pub fn finish(&mut self, complete: bool) -> Option<ChannelList> {
+        if self.done {
+            return None;
+        }
+        self.done = true;
+        Some(ChannelList {
+            entries: mem::take(&mut self.entries),
+            complete,
+        })
+    }
+
+}
+
+#[test]
+fn test_list_param() {
+    let filter = ListFilter::new().mask("#rust*").more_users_than(10).created_less_than(60);
+    assert_eq!(filter.param(None), Some("#rust*,>10,C<60".into()));
+    assert_eq!(filter.param(Some("U")), Some(">10".into()));
+    assert_eq!(filter.param(Some("")), None);
+    assert_eq!(ListFilter::new().mask("#rust").param(Some("")), Some("#rust".into()));
+}
+
+#[test]
+fn test_pending_list() {
+    let entry = |channel: &str, users| ListEntry { channel: channel.into(), users, topic: String::new() };
+    let mut list = PendingList::new(ListFilter::new().more_users_than(5).max_entries(2));
+
+    assert!(list.push(&entry("#a", 10)));
+    assert!(!list.push(&entry("#b", 3)));
+    assert!(!list.is_over_limit());
+    assert!(list.push(&entry("#c", 6)));
+    assert!(list.is_over_limit());
+
+    let result = list.finish(false).unwrap();
+    assert_eq!(result.entries.len(), 2);
+    assert!(!result.complete);
+    assert!(!list.push(&entry("#d", 20)));
+    assert_eq!(list.finish(true), None);
+}
@@ -1,7 +1,10 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use loirc::Event;
-use {Channel, ChannelUser, ChannelUserStatus, Code, Irc, Message, PrefixUser};
+use list::{ChannelList, ListEntry};
+use replies::Reply;
+use {Channel, ChannelUser, ChannelUserStatus, Code, DisconnectReason, Irc, JoinFailure, Message, PrefixUser};
 
 /// Implement this trait to handle events.
 pub trait Listener {
@@ -25,6 +28,12 @@ pub trait Listener {
     #[allow(unused_variables)]
     fn error_msg(&mut self, irc: Arc<Irc>, code: &Code, err: &Message) {}
 
+    /// Any numeric reply which has a typed representation.
+    ///
+    /// This is called after `msg`, and after `error_msg` for errors.
+    #[allow(unused_variables)]
+    fn reply(&mut self, irc: Arc<Irc>, reply: &Reply) {}
+
     /// When the connection is closed.
     ///
     /// It can happen if you manually close the connection, if you set the `ReconnectionSettings`
@@ -33,8 +42,11 @@ pub trait Listener {
     fn close(&mut self, irc: Arc<Irc>, reason: &str) {}
 
     /// When the connection is broken.
+    ///
+    /// The reason is the one the server gave in an ERROR message, if it sent one before
+    /// closing the connection.
     #[allow(unused_variables)]
-    fn disconnect(&mut self, irc: Arc<Irc>) {}
+    fn disconnect(&mut self, irc: Arc<Irc>, reason: Option<&DisconnectReason>) {}
 
     /// When an attempt to reconnect is made.
     #[allow(unused_variables)]
@@ -48,10 +60,50 @@ pub trait Listener {
     #[allow(unused_variables)]
     fn welcome(&mut self, irc: Arc<Irc>) {}
 
+    /// When the message of the day is received, or when the server says there is none.
+    ///
+    /// The server sends it at the end of the registration, so this is where the connection is
+    /// ready to be used. It's also called when the MOTD is requested again later.
+    #[allow(unused_variables)]
+    fn motd(&mut self, irc: Arc<Irc>, motd: Option<Arc<String>>) {}
+
+    /// When a channel of a channel list is received.
+    ///
+    /// Channels filtered out by the `ListFilter` are not given.
+    #[allow(unused_variables)]
+    fn list_entry(&mut self, irc: Arc<Irc>, entry: &ListEntry) {}
+
+    /// When a channel list is over, or was stopped by one of the limits of its `ListFilter`.
+    #[allow(unused_variables)]
+    fn list_end(&mut self, irc: Arc<Irc>, list: &ChannelList) {}
+
     /// When the client sucessfully joins a channel.
     #[allow(unused_variables)]
     fn channel_join(&mut self, irc: Arc<Irc>, channel: Arc<Channel>) {}
 
+    /// When a channel we were in before a reconnection is synchronized again.
+    ///
+    /// Called after `channel_join`, with the users who joined and the users who left the
+    /// channel while we were disconnected.
+    #[allow(unused_variables)]
+    fn channel_resync(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, joined: &[Arc<ChannelUser>],
+                      left: &[Arc<ChannelUser>]) {}
+
+    /// When a user invites someone to a channel.
+    ///
+    /// The target is usually us, but servers with `invite-notify` also tell channel operators
+    /// about the invites sent by others. Invites from users matching `Settings::join_on_invite`
+    /// are followed before this is called.
+    #[allow(unused_variables)]
+    fn invite(&mut self, irc: Arc<Irc>, inviter: &PrefixUser, target: &str, channel: &str) {}
+
+    /// When the server refuses to let us join a channel.
+    ///
+    /// This is only called for channels joined with `IrcWrite::join`, so other errors about
+    /// the same channel are not mistaken for a failed join.
+    #[allow(unused_variables)]
+    fn join_failed(&mut self, irc: Arc<Irc>, channel: &str, reason: JoinFailure, message: &str) {}
+
     /// When a user joins a channel we are listening on.
     #[allow(unused_variables)]
     fn user_join(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, user: Arc<ChannelUser>) {}
@@ -99,7 +151,29 @@ pub trait Listener {
     #[allow(unused_variables)]
     fn nick_change(&mut self, irc: Arc<Irc>, oldnick: &str, newnick: &str) {}
 
+    /// When we message a user who is away, RPL_AWAY.
+    ///
+    /// Servers send it in reply to PRIVMSG and WHOIS.
+    #[allow(unused_variables)]
+    fn user_away(&mut self, irc: Arc<Irc>, nickname: &str, message: &str) {}
+
+    /// When the lag of the connection is measured, see the `lag` module.
+    ///
+    /// The lag is the average of the last measures, like `Irc::lag`.
+    #[allow(unused_variables)]
+    fn lag(&mut self, irc: Arc<Irc>, lag: Duration) {}
+
+    /// When a watched nickname comes online, see `Irc::watch`.
+    #[allow(unused_variables)]
+    fn user_online(&mut self, irc: Arc<Irc>, nickname: &str) {}
+
+    /// When a watched nickname goes offline, see `Irc::watch`.
+    #[allow(unused_variables)]
+    fn user_offline(&mut self, irc: Arc<Irc>, nickname: &str) {}
+
     /// When a user gets kicked from a channel.
+    ///
+    /// When we are the one kicked, the channel is no longer in `Irc::channels`.
     #[allow(unused_variables)]
     fn kick(&mut self, irc: Arc<Irc>, channel: Arc<Channel>, user: Arc<ChannelUser>) {}
 
@@ -0,0 +1,213 @@
+//! Client managing connections to several networks from a single dispatcher.
+//!
+//! Every connection has its own `Settings` and `Irc` state, but all their events go through the
+//! same listener or chain. The name of the network an event comes from is available with
+//! `Irc::network`, and `Networks` gives access to the other connections by name.
+//!
+//! ```ignore
+//! let client = MultiClient::new()
+//!     .network(Settings::new("irc.freenode.net:6667", "relay").network("freenode"))
+//!     .network(Settings::new("irc.oftc.net:6667", "relay").network("oftc"));
+//! let relay = Relay { networks: client.networks() };
+//! client.dispatch(relay).unwrap();
+//! ```
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use chain::Chain;
+use core::{Dispatch, Error, Irc};
+use event::Event;
+use listener::Listener;
+use settings::Settings;
+
+/// Handle to the connections of a `MultiClient`, by network name.
+///
+/// The handle can be cloned and used from any thread. Connections are added to it as soon
+/// as they are established.
+#[derive(Clone, Default)]
+pub struct Networks {
+    networks: Arc<Mutex<HashMap<String, Arc<Irc>>>>,
+}
+
+impl Networks {
+
+    /// Get the connection to a network by name.
+    pub fn get(&self, network: &str) -> Option<Arc<Irc>> {
+        self.networks.lock().unwrap().get(network).cloned()
+    }
+
+    /// Get the names of the networks.
+    pub fn names(&self) -> Vec<String> {
+        self.networks.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Get the list of connections.
+    pub fn all(&self) -> Vec<Arc<Irc>> {
+        self.networks.lock().unwrap().values().cloned().collect()
+    }
+
+    fn insert(&self, irc: Arc<Irc>) {
+        self.networks.lock().unwrap().insert(irc.network().into(), irc);
+    }
+
+}
+
+/// A client connected to several networks.
+///
+/// The name of each network is taken from its `Settings`, so it must be unique:
+/// `Error::DuplicateNetwork` is returned by `dispatch` otherwise.
+#[derive(Default)]
+pub struct MultiClient<'a> {
+    settings: Vec<Settings<'a>>,
+    networks: Networks,
+}
+
+impl<'a> MultiClient<'a> {
+
+    /// Create a client without any network.
+    pub fn new() -> MultiClient<'a> {
+        MultiClient {
+            settings: Vec::new(),
+            networks: Networks::default(),
+        }
+    }
+
+    /// Add a network to connect to.
+    pub fn network(mut self, settings: Settings<'a>) -> MultiClient<'a> {
+        self.settings.push(settings);
+        self
+    }
+
+    /// Get a handle to the connections, which can be given to the listener.
+    pub fn networks(&self) -> Networks {
+        self.networks.clone()
+    }
+
+    /// Connect to every network and begin dispatching their events to the given `Listener`.
+    pub fn dispatch<L>(self, listener: L) -> Result<(), Error>
+        where L: Listener + 'a
+    {
+        self.dispatch_chain(Chain::new().listener(listener))
+    }
+
+    /// Connect to every network and begin dispatching their events through the given `Chain`.
+    ///
+    /// Listeners and middlewares added with `Irc::add_listener` and `Irc::add_middleware`
+    /// only see the events of the network they were added from.
+    ///
+    /// This returns once every connection is closed, or fails before connecting if two
+    /// networks have the same name.
+    pub fn dispatch_chain(self, chain: Chain<'a>) -> Result<(), Error> {
+        check_names(&self.settings)?;
+
+        let shared = Rc::new(RefCell::new(chain));
+        let (sender, receiver) = mpsc::channel();
+        let mut dispatches = Vec::new();
+
+        for settings in self.settings {
+            let (dispatch, reader) = match Dispatch::connect(forward_to(&shared), settings) {
+                Ok(res) => res,
+                Err(err) => {
+                    for irc in self.networks.all() {
+                        let _ = irc.close();
+                    }
+                    return Err(err);
+                }
+            };
+
+            self.networks.insert(dispatch.irc());
+
+            let index = dispatches.len();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for event in reader.iter() {
+                    if sender.send((index, event)).is_err() {
+                        break;
+                    }
+                }
+            });
+            dispatches.push(dispatch);
+        }
+
+        drop(sender);
+
+        for (index, event) in receiver.iter() {
+            dispatches[index].feed(&event);
+        }
+
+        Ok(())
+    }
+
+}
+
+// Chain of a single connection, which gives its events to the shared chain.
+fn forward_to<'a>(shared: &Rc<RefCell<Chain<'a>>>) -> Chain<'a> {
+    let shared = shared.clone();
+    Chain::new().middleware(move |irc: Arc<Irc>, event: &mut Event| shared.borrow_mut().emit(&irc, event))
+}
+
+fn check_names(settings: &[Settings]) -> Result<(), Error> {
+    for (idx, network) in settings.iter().map(|s| s.network).enumerate() {
+        if settings[..idx].iter().any(|s| s.network == network) {
+            return Err(Error::DuplicateNetwork(network.into()));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_names() {
+    let mut settings = vec![
+        Settings::new("irc.freenode.net:6667", "relay").network("freenode"),
+        Settings::new("irc.oftc.net:6667", "relay").network("oftc"),
+    ];
+    assert!(check_names(&settings).is_ok());
+
+    settings.push(Settings::new("chat.freenode.net:6667", "relay").network("freenode"));
+    match check_names(&settings) {
+        Err(Error::DuplicateNetwork(network)) => assert_eq!(network, "freenode"),
+        _ => panic!("duplicate network accepted"),
+    }
+}
+
+#[cfg(test)]
+use loirc::PrefixUser;
+#[cfg(test)]
+use testing::{message, TestServer};
+
+// Logs the private messages it receives with the network they come from.
+#[cfg(test)]
+struct Logger(&'static str, Arc<Mutex<Vec<String>>>);
+
+#[cfg(test)]
+impl Listener for Logger {
+    fn private_msg(&mut self, irc: Arc<Irc>, _: &PrefixUser, message: &str) {
+        self.1.lock().unwrap().push(format!("{}:{}:{}", self.0, irc.network(), message));
+        if message == "add" {
+            irc.add_listener(Logger("local", self.1.clone()));
+        }
+    }
+}
+
+#[test]
+fn test_shared_chain() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let shared = Rc::new(RefCell::new(Chain::new().listener(Logger("shared", log.clone()))));
+    let (server_a, server_b) = (TestServer::new(), TestServer::new());
+    let (mut a, _client_a) = server_a.connect(forward_to(&shared), server_a.settings().network("a"));
+    let (mut b, _client_b) = server_b.connect(forward_to(&shared), server_b.settings().network("b"));
+    let privmsg = |text: &str| message(&format!(":alice!a@host PRIVMSG me :{}", text));
+
+    a.feed(&privmsg("one"));
+    b.feed(&privmsg("two"));
+    a.feed(&privmsg("add"));
+    b.feed(&privmsg("three"));
+    a.feed(&privmsg("four"));
+    assert_eq!(*log.lock().unwrap(), [
+        "shared:a:one", "shared:b:two", "shared:a:add", "shared:b:three", "shared:a:four", "local:a:four",
+    ]);
+}
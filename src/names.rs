@@ -0,0 +1,168 @@
+//! Nicknames and channel names checked against the rules of the server.
+//!
+//! `Nickname` and `ChannelName` can only be created from valid names, using the limits the
+//! server advertised in its ISUPPORT reply. They dereference to `str`, so they can be given
+//! to `IrcWrite` methods directly.
+//!
+//! ```ignore
+//! let channel = ChannelName::new("#rust", &irc.isupport())?;
+//! irc.join(&channel, None)?;
+//! ```
+
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::ops::Deref;
+
+use isupport::ISupport;
+
+/// Reasons a name is not valid.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NameError {
+    /// The name is empty.
+    Empty,
+    /// The name is longer than the limit of the server.
+    TooLong(usize),
+    /// The name contains a character which is not allowed, or not allowed at this position.
+    ForbiddenChar(char),
+    /// The channel name doesn't start with one of the channel types of the server.
+    NotAChannel,
+}
+
+impl Display for NameError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            NameError::Empty => write!(f, "Name is empty"),
+            NameError::TooLong(max) => write!(f, "Name is longer than {} characters", max),
+            NameError::ForbiddenChar(c) => write!(f, "Name contains forbidden character {:?}", c),
+            NameError::NotAChannel => write!(f, "Name does not start with a channel type"),
+        }
+    }
+}
+
+impl error::Error for NameError {}
+
+/// A valid nickname.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Nickname(String);
+
+impl Nickname {
+
+    /// Check a nickname against the rules of the server.
+    ///
+    /// Nicknames can't contain spaces, commas, wildcards, `!`, `@` or control characters, and
+    /// can't start with a digit, `-`, `:` or a channel type.
+    pub fn new(name: &str, isupport: &ISupport) -> Result<Nickname, NameError> {
+        let first = name.chars().next().ok_or(NameError::Empty)?;
+        if let Some(max) = isupport.nicklen() {
+            if name.chars().count() > max {
+                return Err(NameError::TooLong(max));
+            }
+        }
+        if first.is_ascii_digit() || first == '-' || first == ':' || isupport.is_channel(name) {
+            return Err(NameError::ForbiddenChar(first));
+        }
+        if let Some(c) = name.chars().find(|&c| " ,*?!@".contains(c) || c.is_control()) {
+            return Err(NameError::ForbiddenChar(c));
+        }
+        Ok(Nickname(name.into()))
+    }
+
+    /// Get the nickname as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+}
+
+/// A valid channel name.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ChannelName(String);
+
+impl ChannelName {
+
+    /// Check a channel name against the rules of the server.
+    ///
+    /// Channel names must start with a channel type and can't contain spaces, commas,
+    /// `^G` or other control characters.
+    pub fn new(name: &str, isupport: &ISupport) -> Result<ChannelName, NameError> {
+        if name.is_empty() {
+            return Err(NameError::Empty);
+        }
+        if !isupport.is_channel(name) {
+            return Err(NameError::NotAChannel);
+        }
+        if let Some(max) = isupport.channellen() {
+            if name.chars().count() > max {
+                return Err(NameError::TooLong(max));
+            }
+        }
+        if let Some(c) = name.chars().find(|&c| c == ' ' || c == ',' || c.is_control()) {
+            return Err(NameError::ForbiddenChar(c));
+        }
+        Ok(ChannelName(name.into()))
+    }
+
+    /// Get the channel name as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+}
+
+macro_rules! impl_str_wrapper {
+    ($name:ident) => {
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(name: $name) -> String {
+                name.0
+            }
+        }
+    };
+}
+
+impl_str_wrapper!(Nickname);
+impl_str_wrapper!(ChannelName);
+
+#[test]
+fn test_nickname() {
+    let mut isupport = ISupport::default();
+    isupport.update(&["NICKLEN=9"]);
+
+    assert_eq!(Nickname::new("alice[m]", &isupport).unwrap().as_str(), "alice[m]");
+    assert_eq!(Nickname::new("", &isupport), Err(NameError::Empty));
+    assert_eq!(Nickname::new("verylongnick", &isupport), Err(NameError::TooLong(9)));
+    assert_eq!(Nickname::new("1alice", &isupport), Err(NameError::ForbiddenChar('1')));
+    assert_eq!(Nickname::new("#alice", &isupport), Err(NameError::ForbiddenChar('#')));
+    assert_eq!(Nickname::new("x :#y", &isupport), Err(NameError::ForbiddenChar(' ')));
+}
+
+#[test]
+fn test_channel_name() {
+    let mut isupport = ISupport::default();
+    isupport.update(&["CHANTYPES=#", "CHANNELLEN=8"]);
+
+    assert_eq!(&*ChannelName::new("#rust", &isupport).unwrap(), "#rust");
+    assert_eq!(ChannelName::new("&rust", &isupport), Err(NameError::NotAChannel));
+    assert_eq!(ChannelName::new("#rustlang", &isupport), Err(NameError::TooLong(8)));
+    assert_eq!(ChannelName::new("#a,#b", &isupport), Err(NameError::ForbiddenChar(',')));
+    assert_eq!(ChannelName::new("#a\x07", &isupport), Err(NameError::ForbiddenChar('\x07')));
+}
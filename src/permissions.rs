@@ -0,0 +1,157 @@
+//! Permission rules for bot commands.
+//!
+//! A `Rule` is evaluated against a `Subject`, which describes who is trying to do something:
+//! their nickname, username and hostname, their services account and their status in the
+//! channel where it happens. Rules can be used on their own, or attached to commands with
+//! `Command::require`.
+//!
+//! ```ignore
+//! let rule = Rule::Any(vec![
+//!     Rule::Status(ChannelUserStatus::Operator),
+//!     Rule::Account("alice".into()),
+//! ]);
+//! ```
+
+use core::ChannelUserStatus;
+use hostmask::Hostmask;
+use isupport::CaseMapping;
+use loirc::PrefixUser;
+
+/// Who a rule is evaluated against.
+#[derive(Clone, Debug)]
+pub struct Subject<'s> {
+    nickname: &'s str,
+    username: &'s str,
+    hostname: &'s str,
+    account: Option<&'s str>,
+    status: Option<ChannelUserStatus>,
+    casemapping: CaseMapping,
+}
+
+impl<'s> Subject<'s> {
+
+    /// Create a subject from a user, without account or channel status.
+    pub fn new(user: &'s PrefixUser) -> Subject<'s> {
+        Subject::from_parts(&user.nickname, &user.username, &user.hostname)
+    }
+
+    /// Create a subject from the parts of a hostmask.
+    pub fn from_parts(nickname: &'s str, username: &'s str, hostname: &'s str) -> Subject<'s> {
+        Subject {
+            nickname,
+            username,
+            hostname,
+            account: None,
+            status: None,
+            casemapping: CaseMapping::default(),
+        }
+    }
+
+    /// Set the services account of the subject.
+    pub fn account(mut self, account: Option<&'s str>) -> Subject<'s> {
+        self.account = account;
+        self
+    }
+
+    /// Set the status of the subject in the channel.
+    pub fn status(mut self, status: Option<ChannelUserStatus>) -> Subject<'s> {
+        self.status = status;
+        self
+    }
+
+    /// Set the case mapping used to compare hostmasks and accounts, `rfc1459` by default.
+    pub fn casemapping(mut self, casemapping: CaseMapping) -> Subject<'s> {
+        self.casemapping = casemapping;
+        self
+    }
+
+}
+
+/// A permission rule.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Rule {
+    /// Allows everyone.
+    Anyone,
+    /// Allows users with at least this status in the channel.
+    ///
+    /// Never allows in private messages, since there is no channel.
+    Status(ChannelUserStatus),
+    /// Allows users logged in with this services account.
+    ///
+    /// The account must be known by `Irc::account`, which learns it from WHOIS replies and
+    /// `ACCOUNT` messages. The client doesn't negotiate the `account-notify` capability, so
+    /// accounts are usually only known after a WHOIS: `Commands` sends one for the sender
+    /// before evaluating a rule which uses accounts. When evaluated by hand, the subject must
+    /// be given the account with `Subject::account`, or the rule never allows.
+    Account(String),
+    /// Allows users matching this mask.
+    Hostmask(Hostmask),
+    /// Allows if every rule allows.
+    All(Vec<Rule>),
+    /// Allows if at least one rule allows.
+    Any(Vec<Rule>),
+    /// Allows if the rule does not allow.
+    Not(Box<Rule>),
+}
+
+impl Rule {
+
+    /// Check if the rule allows the subject.
+    pub fn allows(&self, subject: &Subject) -> bool {
+        match *self {
+            Rule::Anyone => true,
+            Rule::Status(status) => subject.status.map(|s| s >= status).unwrap_or(false),
+            Rule::Account(ref account) => {
+                subject.account.map(|a| subject.casemapping.eq(a, account)).unwrap_or(false)
+            }
+            Rule::Hostmask(ref mask) => {
+                mask.matches_parts(subject.nickname, subject.username, subject.hostname, subject.casemapping)
+            }
+            Rule::All(ref rules) => rules.iter().all(|r| r.allows(subject)),
+            Rule::Any(ref rules) => rules.iter().any(|r| r.allows(subject)),
+            Rule::Not(ref rule) => !rule.allows(subject),
+        }
+    }
+
+    /// Check if the rule depends on the services account of the subject.
+    pub fn needs_account(&self) -> bool {
+        match *self {
+            Rule::Account(_) => true,
+            Rule::All(ref rules) | Rule::Any(ref rules) => rules.iter().any(|r| r.needs_account()),
+            Rule::Not(ref rule) => rule.needs_account(),
+            _ => false,
+        }
+    }
+
+}
+
+#[test]
+fn test_rules() {
+    let op = Subject::from_parts("alice", "alice", "example.com")
+        .account(Some("Alice"))
+        .status(Some(ChannelUserStatus::Operator));
+    let voice = Subject::from_parts("bob", "bob", "example.org")
+        .status(Some(ChannelUserStatus::Voice));
+    let private = Subject::from_parts("carol", "carol", "example.net");
+
+    let ops = Rule::Status(ChannelUserStatus::HalfOperator);
+    assert!(ops.allows(&op));
+    assert!(!ops.allows(&voice));
+    assert!(!ops.allows(&private));
+
+    let account = Rule::Account("alice".into());
+    assert!(account.allows(&op));
+    assert!(!account.allows(&voice));
+
+    let mask = Rule::Hostmask(Hostmask::parse("*!*@*.org"));
+    assert!(mask.allows(&voice));
+    assert!(!mask.allows(&op));
+
+    let rule = Rule::All(vec![Rule::Any(vec![ops, mask]), Rule::Not(Box::new(Rule::Hostmask(Hostmask::parse("bob!*@*"))))]);
+    assert!(rule.allows(&op));
+    assert!(!rule.allows(&voice));
+    assert!(!rule.allows(&private));
+    assert!(Rule::Anyone.allows(&private));
+    assert!(!rule.needs_account());
+    assert!(Rule::Not(Box::new(Rule::Any(vec![Rule::Anyone, account]))).needs_account());
+}
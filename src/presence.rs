@@ -0,0 +1,218 @@
+//! Presence of watched nicknames.
+//!
+//! Nicknames added with `Irc::watch` are kept in a watch list, and `Listener::user_online`
+//! and `Listener::user_offline` are called when they come and go. Servers which advertise
+//! `MONITOR` in ISUPPORT notify us by themselves, other servers are asked with ISON every
+//! `Settings::ison_interval`. When the monitor list of the server is full, the nicknames it
+//! refused are asked with ISON as well. The watch list is kept across reconnections.
+//!
+//! ```ignore
+//! irc.watch(&["alice", "bob"])?;
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+
+use isupport::CaseMapping;
+
+/// How the server tells us about the watched nicknames.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Method {
+    Monitor,
+    Ison,
+}
+
+/// Watch list and known presence of the watched nicknames.
+#[derive(Debug, Default)]
+pub(crate) struct Presence {
+    watched: Vec<String>,
+    // Presence of the watched nicknames, by lowercase nickname, once known.
+    online: HashMap<String, bool>,
+    // Nicknames of each ISON command waiting for a reply.
+    pending_ison: VecDeque<Vec<String>>,
+    // Nicknames the server refused to monitor, asked with ISON instead.
+    refused: Vec<String>,
+    method: Option<Method>,
+    // Changed every time the method changes, to stop the ISON polling of older connections.
+    generation: u64,
+}
+
+impl Presence {
+
+    /// Add nicknames to the watch list, returns the ones which were not watched yet.
+    pub fn watch(&mut self, nicknames: &[&str], casemapping: CaseMapping) -> Vec<String> {
+        let mut added = Vec::new();
+        for &nickname in nicknames {
+            if !self.watched.iter().any(|w| casemapping.eq(w, nickname)) {
+                self.watched.push(nickname.into());
+                added.push(nickname.into());
+            }
+        }
+        added
+    }
+
+    /// Remove nicknames from the watch list, returns the ones which were watched.
+    pub fn unwatch(&mut self, nicknames: &[&str], casemapping: CaseMapping) -> Vec<String> {
+        let mut removed = Vec::new();
+        for &nickname in nicknames {
+            if let Some(pos) = self.watched.iter().position(|w| casemapping.eq(w, nickname)) {
+                removed.push(self.watched.remove(pos));
+                self.online.remove(&casemapping.normalize(nickname));
+                self.refused.retain(|r| !casemapping.eq(r, nickname));
+            }
+        }
+        removed
+    }
+
+    pub fn watched(&self) -> &[String] {
+        &self.watched
+    }
+
+    pub fn is_online(&self, nickname: &str, casemapping: CaseMapping) -> Option<bool> {
+        self.online.get(&casemapping.normalize(nickname)).cloned()
+    }
+
+    /// Record the presence of a nickname, returns true if it changed.
+    ///
+    /// Nicknames which are not watched are ignored.
+    pub fn set_online(&mut self, nickname: &str, online: bool, casemapping: CaseMapping) -> bool {
+        if !self.watched.iter().any(|w| casemapping.eq(w, nickname)) {
+            return false;
+        }
+        self.online.insert(casemapping.normalize(nickname), online) != Some(online)
+    }
+
+    /// Record the nicknames the server refused to monitor, returns the ones to ask with ISON.
+    ///
+    /// Nicknames which are not watched are ignored.
+    pub fn monitor_refused(&mut self, nicknames: &[String], casemapping: CaseMapping) -> Vec<String> {
+        let mut added = Vec::new();
+        for nickname in nicknames {
+            let watched = self.watched.iter().any(|w| casemapping.eq(w, nickname));
+            if watched && !self.refused.iter().any(|r| casemapping.eq(r, nickname)) {
+                self.refused.push(nickname.clone());
+                added.push(nickname.clone());
+            }
+        }
+        added
+    }
+
+    /// Get the nicknames to ask with ISON at each poll.
+    pub fn ison_targets(&self) -> Vec<String> {
+        match self.method {
+            Some(Method::Ison) => self.watched.clone(),
+            Some(Method::Monitor) => self.refused.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Record the nicknames of an ISON command, as it's written.
+    pub fn ison_sent(&mut self, nicknames: Vec<String>) {
+        self.pending_ison.push_back(nicknames);
+    }
+
+    /// Handle the reply to the oldest ISON command, returns the changes of presence.
+    pub fn ison_reply(&mut self, online: &[String], casemapping: CaseMapping) -> Vec<(String, bool)> {
+        let asked = match self.pending_ison.pop_front() {
+            Some(asked) => asked,
+            None => return Vec::new(),
+        };
+        asked.into_iter()
+            .map(|nickname| {
+                let is_online = online.iter().any(|o| casemapping.eq(o, &nickname));
+                (nickname, is_online)
+            })
+            .filter(|&(ref nickname, is_online)| self.set_online(nickname, is_online, casemapping))
+            .collect()
+    }
+
+    pub fn method(&self) -> Option<Method> {
+        self.method
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Start tracking the presence once registered, returns the new generation.
+    pub fn start(&mut self, method: Method) -> u64 {
+        self.method = Some(method);
+        self.refused.clear();
+        self.generation += 1;
+        self.generation
+    }
+
+    /// Stop tracking the presence when the connection is lost.
+    ///
+    /// The known presence is kept, so that only actual changes are reported after reconnecting.
+    pub fn stop(&mut self) {
+        self.method = None;
+        self.generation += 1;
+        self.pending_ison.clear();
+    }
+
+}
+
+/// Group nicknames so that each group fits in a single command.
+pub(crate) fn batches(nicknames: &[String], max_length: usize, max_count: usize) -> Vec<Vec<String>> {
+    let mut batches: Vec<Vec<String>> = Vec::new();
+    let mut length = 0;
+    for nickname in nicknames {
+        match batches.last_mut() {
+            Some(batch) if batch.len() < max_count && length + nickname.len() < max_length => {
+                batch.push(nickname.clone())
+            }
+            _ => {
+                batches.push(vec![nickname.clone()]);
+                length = 0;
+            }
+        }
+        length += nickname.len() + 1;
+    }
+    batches
+}
+
+#[test]
+fn test_presence() {
+    let cm = CaseMapping::Rfc1459;
+    let mut presence = Presence::default();
+
+    assert_eq!(presence.watch(&["Alice", "bob"], cm), vec!["Alice", "bob"]);
+    assert_eq!(presence.watch(&["alice", "carol"], cm), vec!["carol"]);
+    assert_eq!(presence.is_online("alice", cm), None);
+
+    presence.ison_sent(vec!["Alice".into(), "bob".into()]);
+    assert_eq!(presence.ison_reply(&["ALICE".into()], cm), vec![("Alice".into(), true), ("bob".into(), false)]);
+    presence.ison_sent(vec!["Alice".into(), "bob".into()]);
+    assert_eq!(presence.ison_reply(&["alice".into()], cm), vec![]);
+    assert_eq!(presence.is_online("alice", cm), Some(true));
+
+    assert!(!presence.set_online("dave", true, cm));
+    assert_eq!(presence.unwatch(&["BOB", "dave"], cm), vec!["bob"]);
+    assert_eq!(presence.watched(), &["Alice", "carol"]);
+}
+
+#[test]
+fn test_monitor_refused() {
+    let cm = CaseMapping::Rfc1459;
+    let mut presence = Presence::default();
+
+    presence.watch(&["alice", "bob", "carol"], cm);
+    presence.start(Method::Monitor);
+    assert!(presence.ison_targets().is_empty());
+    assert_eq!(presence.monitor_refused(&["BOB".into(), "CAROL".into(), "dave".into()], cm), vec!["BOB", "CAROL"]);
+    assert_eq!(presence.monitor_refused(&["bob".into()], cm), Vec::<String>::new());
+    presence.unwatch(&["carol"], cm);
+    assert_eq!(presence.ison_targets(), vec!["BOB"]);
+
+    presence.start(Method::Ison);
+    assert_eq!(presence.ison_targets(), vec!["alice", "bob"]);
+}
+
+#[test]
+fn test_batches() {
+    let nicknames: Vec<String> = vec!["alice".into(), "bob".into(), "carol".into()];
+    assert_eq!(batches(&nicknames, 10, 5), vec![vec!["alice", "bob"], vec!["carol"]]);
+    assert_eq!(batches(&nicknames, 400, 2), vec![vec!["alice", "bob"], vec!["carol"]]);
+    assert_eq!(batches(&nicknames, 400, 5).len(), 1);
+    assert!(batches(&[], 400, 5).is_empty());
+}
@@ -0,0 +1,148 @@
+//! Rejoining channels after being kicked.
+//!
+//! When enabled with `Settings::kick_rejoin`, the client rejoins a channel it was kicked from
+//! after a delay. The delay doubles with each kick or failed attempt in the same time window,
+//! and the client gives up after too many attempts, or as soon as the server says we are
+//! banned, not invited, don't have the right key, need to be logged in, or can't join the
+//! channel at all.
+//!
+//! ```ignore
+//! let settings = Settings::new("irc.freenode.net:6667", "keeper")
+//!     .kick_rejoin(Some(KickRejoin { max_attempts: 3, ..KickRejoin::default() }));
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use loirc::Code;
+use JoinFailure;
+
+/// Settings of the rejoin after a kick.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KickRejoin {
+    /// Delay before the first attempt to rejoin.
+    pub delay: Duration,
+    /// Longest delay between attempts, once doubled.
+    pub max_delay: Duration,
+    /// Maximum number of attempts to rejoin a channel within `window`.
+    pub max_attempts: u32,
+    /// Time window in which attempts are counted.
+    pub window: Duration,
+}
+
+impl Default for KickRejoin {
+    /// Rejoin after 2 seconds, at most 5 times in 10 minutes, waiting at most 5 minutes.
+    fn default() -> KickRejoin {
+        KickRejoin {
+            delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(300),
+            max_attempts: 5,
+            window: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Keeps track of the attempts to rejoin each channel.
+#[derive(Debug)]
+pub(crate) struct Rejoiner {
+    settings: KickRejoin,
+    attempts: HashMap<String, Vec<Instant>>,
+    pending: HashSet<String>,
+}
+
+impl Rejoiner {
+
+    pub fn new(settings: KickRejoin) -> Rejoiner {
+        Rejoiner {
+            settings,
+            attempts: HashMap::new(),
+            pending: HashSet::new(),
+        }
+    }
+
+    /// We were kicked from a channel, returns the delay before rejoining it.
+    pub fn kicked(&mut self, channel_id: &str) -> Option<Duration> {
+        self.attempt(channel_id)
+    }
+
+    /// Joining a channel failed, returns the delay before trying again.
+    ///
+    /// Only channels we are trying to rejoin are retried, and only when the channel is full,
+    /// the other JOIN failures won't go away by waiting. Codes which are not JOIN failures
+    /// are ignored.
+    pub fn failed(&mut self, channel_id: &str, code: &Code) -> Option<Duration> {
+        if JoinFailure::from_code(code).is_none() || !self.pending.remove(channel_id) {
+            return None;
+        }
+        match *code {
+            Code::ErrChannelisfull => self.attempt(channel_id),
+            _ => None,
+        }
+    }
+
+    /// We joined a channel.
+    pub fn joined(&mut self, channel_id: &str) {
+        self.pending.remove(channel_id);
+    }
+
+    fn attempt(&mut self, channel_id: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let window = self.settings.window;
+        let attempts = self.attempts.entry(channel_id.into()).or_default();
+        attempts.retain(|&t| now.duration_since(t) < window);
+
+        if attempts.len() >= self.settings.max_attempts as usize {
+            return None;
+        }
+
+        let factor = 1u32.checked_shl(attempts.len() as u32).unwrap_or(u32::MAX);
+        let delay = self.settings.delay.checked_mul(factor).unwrap_or(self.settings.max_delay);
+        attempts.push(now);
+        self.pending.insert(channel_id.into());
+        Some(delay.min(self.settings.max_delay))
+    }
+
+}
+
+#[test]
+fn test_rejoiner_backoff() {
+    let mut rejoiner = Rejoiner::new(KickRejoin {
+        delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(3),
+        max_attempts: 4,
+        window: Duration::from_secs(60),
+    });
+
+    assert_eq!(rejoiner.kicked("#a"), Some(Duration::from_secs(1)));
+    rejoiner.joined("#a");
+    assert_eq!(rejoiner.kicked("#a"), Some(Duration::from_secs(2)));
+    assert_eq!(rejoiner.failed("#a", &Code::ErrChannelisfull), Some(Duration::from_secs(3)));
+    assert_eq!(rejoiner.failed("#a", &Code::ErrChannelisfull), Some(Duration::from_secs(3)));
+    assert_eq!(rejoiner.failed("#a", &Code::ErrChannelisfull), None);
+    assert_eq!(rejoiner.kicked("#b"), Some(Duration::from_secs(1)));
+}
+
+#[test]
+fn test_rejoiner_stops() {
+    let mut rejoiner = Rejoiner::new(KickRejoin::default());
+
+    assert!(rejoiner.kicked("#a").is_some());
+    assert_eq!(rejoiner.failed("#a", &Code::ErrBannedfromchan), None);
+    assert_eq!(rejoiner.failed("#a", &Code::ErrChannelisfull), None);
+    assert_eq!(rejoiner.failed("#b", &Code::ErrChannelisfull), None);
+
+    for code in &[Code::ErrNochanmodes, Code::ErrNosuchchannel, Code::ErrToomanychannels] {
+        assert!(rejoiner.kicked("#c").is_some());
+        assert_eq!(rejoiner.failed("#c", code), None);
+    }
+}
+
+#[test]
+fn test_rejoiner_ignores_other_errors() {
+    let mut rejoiner = Rejoiner::new(KickRejoin::default());
+
+    assert!(rejoiner.kicked("#a").is_some());
+    assert_eq!(rejoiner.failed("#a", &Code::ErrChanoprivsneeded), None);
+    assert_eq!(rejoiner.failed("#a", &Code::ErrCannotsendtochan), None);
+    assert!(rejoiner.failed("#a", &Code::ErrChannelisfull).is_some());
+}
@@ -0,0 +1,483 @@
+//! Typed numeric replies.
+//!
+//! `Reply::parse` turns the numeric replies of a `Message` into a `Reply`, so the meaning of
+//! each argument doesn't have to be looked up. The `reply` callback of `Listener` receives
+//! every numeric the server sends which has a typed representation.
+//!
+//! ```ignore
+//! fn reply(&mut self, irc: Arc<Irc>, reply: &Reply) {
+//!     if let Reply::WhoisUser { ref nickname, ref hostname, .. } = *reply {
+//!         println!("{} is connected from {}", nickname, hostname);
+//!     }
+//! }
+//! ```
+
+use std::str::FromStr;
+
+use loirc::{Code, Message};
+
+/// A numeric reply sent by the server.
+///
+/// The first argument of every numeric, our own nickname, is left out.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Reply {
+    /// RPL_WELCOME (001), the first reply after registration.
+    Welcome {
+        /// Our nickname, as the server knows it.
+        nickname: String,
+        /// Welcome text.
+        message: String,
+    },
+    /// RPL_YOURHOST (002).
+    YourHost {
+        /// Text with the name and version of the server.
+        message: String,
+    },
+    /// RPL_CREATED (003).
+    Created {
+        /// Text with the creation date of the server.
+        message: String,
+    },
+    /// RPL_MYINFO (004).
+    MyInfo {
+        /// Name of the server.
+        server: String,
+        /// Version of the server.
+        version: String,
+        /// Available user modes.
+        user_modes: String,
+        /// Available channel modes.
+        channel_modes: String,
+    },
+    /// RPL_ISUPPORT (005).
+    ISupport {
+        /// Tokens such as `CHANTYPES=#` or `-EXCEPTS`.
+        tokens: Vec<String>,
+    },
+    /// RPL_LUSERCLIENT (251).
+    LuserClient {
+        /// Text with the number of users and servers.
+        message: String,
+    },
+    /// RPL_LUSEROP (252).
+    LuserOp {
+        /// Number of operators online.
+        count: u32,
+    },
+    /// RPL_LUSERUNKNOWN (253).
+    LuserUnknown {
+        /// Number of unregistered connections.
+        count: u32,
+    },
+    /// RPL_LUSERCHANNELS (254).
+    LuserChannels {
+        /// Number of channels.
+        count: u32,
+    },
+    /// RPL_LUSERME (255).
+    LuserMe {
+        /// Text with the number of clients and servers of this server.
+        message: String,
+    },
+    /// RPL_AWAY (301).
+    Away {
+        /// The user who is away.
+        nickname: String,
+        /// Away message.
+        message: String,
+    },
+    /// RPL_ISON (303).
+    IsOn {
+        /// The nicknames of the ISON command which are online.
+        nicknames: Vec<String>,
+    },
+    /// RPL_UNAWAY (305), we are no longer marked as away.
+    Unaway,
+    /// RPL_NOWAWAY (306), we are marked as away.
+    NowAway,
+    /// RPL_WHOISUSER (311).
+    WhoisUser {
+        /// Nickname of the user.
+        nickname: String,
+        /// Username of the user.
+        username: String,
+        /// Hostname of the user.
+        hostname: String,
+        /// Real name of the user.
+        realname: String,
+    },
+    /// RPL_WHOISSERVER (312).
+    WhoisServer {
+        /// Nickname of the user.
+        nickname: String,
+        /// Server the user is connected to.
+        server: String,
+        /// Description of the server.
+        info: String,
+    },
+    /// RPL_WHOISOPERATOR (313).
+    WhoisOperator {
+        /// Nickname of the user.
+        nickname: String,
+    },
+    /// RPL_WHOISIDLE (317).
+    WhoisIdle {
+        /// Nickname of the user.
+        nickname: String,
+        /// Seconds since the last message of the user.
+        idle: u64,
+        /// When the user connected, in seconds since the Unix epoch, if the server tells.
+        signon: Option<u64>,
+    },
+    /// RPL_ENDOFWHOIS (318).
+    EndOfWhois {
+        /// Nickname of the user.
+        nickname: String,
+    },
+    /// RPL_WHOISCHANNELS (319).
+    WhoisChannels {
+        /// Nickname of the user.
+        nickname: String,
+        /// Channels of the user, with their status prefix like `@#channel`.
+        channels: Vec<String>,
+    },
+    /// RPL_WHOISACCOUNT (330).
+    WhoisAccount {
+        /// Nickname of the user.
+        nickname: String,
+        /// Services account of the user.
+        account: String,
+    },
+    /// RPL_LISTSTART (321).
+    ListStart,
+    /// RPL_LIST (322).
+    List {
+        /// Name of the channel.
+        channel: String,
+        /// Number of users in the channel.
+        users: u32,
+        /// Topic of the channel, which may start with the channel modes.
+        topic: String,
+    },
+    /// RPL_LISTEND (323).
+    ListEnd,
+    /// RPL_CHANNELMODEIS (324).
+    ChannelModeIs {
+        /// Name of the channel.
+        channel: String,
+        /// Mode string, like `+ntk`.
+        modes: String,
+        /// Parameters of the modes.
+        params: Vec<String>,
+    },
+    /// RPL_NOTOPIC (331).
+    NoTopic {
+        /// Name of the channel.
+        channel: String,
+    },
+    /// RPL_TOPIC (332).
+    Topic {
+        /// Name of the channel.
+        channel: String,
+        /// Topic of the channel.
+        topic: String,
+    },
+    /// RPL_INVITING (341), our invitation was sent.
+    Inviting {
+        /// Nickname of the invited user.
+        nickname: String,
+        /// Name of the channel.
+        channel: String,
+    },
+    /// RPL_INVITELIST (346).
+    InviteList {
+        /// Name of the channel.
+        channel: String,
+        /// Invited mask.
+        mask: String,
+        /// Who set the invite, if the server tells.
+        set_by: Option<String>,
+        /// When the invite was set, in seconds since the Unix epoch, if the server tells.
+        set_at: Option<u64>,
+    },
+    /// RPL_ENDOFINVITELIST (347).
+    EndOfInviteList {
+        /// Name of the channel.
+        channel: String,
+    },
+    /// RPL_NAMREPLY (353).
+    Names {
+        /// `=` for public channels, `*` for private ones and `@` for secret ones.
+        visibility: char,
+        /// Name of the channel.
+        channel: String,
+        /// Nicknames, with their status prefix like `@alice`.
+        names: Vec<String>,
+    },
+    /// RPL_ENDOFNAMES (366).
+    EndOfNames {
+        /// Name of the channel.
+        channel: String,
+    },
+    /// RPL_BANLIST (367).
+    BanList {
+        /// Name of the channel.
+        channel: String,
+        /// Banned mask.
+        mask: String,
+        /// Who set the ban, if the server tells.
+        set_by: Option<String>,
+        /// When the ban was set, in seconds since the Unix epoch, if the server tells.
+        set_at: Option<u64>,
+    },
+    /// RPL_ENDOFBANLIST (368).
+    EndOfBanList {
+        /// Name of the channel.
+        channel: String,
+    },
+    /// RPL_MOTDSTART (375).
+    MotdStart {
+        /// Text with the name of the server.
+        message: String,
+    },
+    /// RPL_MOTD (372), a line of the message of the day.
+    Motd {
+        /// The line, without the leading `- `.
+        line: String,
+    },
+    /// RPL_ENDOFMOTD (376).
+    EndOfMotd,
+    /// RPL_MONONLINE (730), monitored users are online.
+    MonOnline {
+        /// The users, as `nick!user@host` or just `nick`.
+        targets: Vec<String>,
+    },
+    /// RPL_MONOFFLINE (731), monitored users are offline.
+    MonOffline {
+        /// Nicknames of the users.
+        nicknames: Vec<String>,
+    },
+    /// RPL_MONLIST (732).
+    MonList {
+        /// Monitored nicknames.
+        nicknames: Vec<String>,
+    },
+    /// RPL_ENDOFMONLIST (733).
+    EndOfMonList,
+    /// ERR_MONLISTFULL (734), the monitor list can't hold more nicknames.
+    MonListFull {
+        /// Size of the monitor list.
+        limit: u32,
+        /// Nicknames which were not added.
+        nicknames: Vec<String>,
+    },
+    /// Any error reply, from 400 to 599.
+    Error {
+        /// Code of the error.
+        code: Code,
+        /// Arguments between our nickname and the error text, such as a channel name.
+        params: Vec<String>,
+        /// Error text.
+        message: String,
+    },
+}
+
+impl Reply {
+
+    /// Parse the numeric reply of a message.
+    ///
+    /// Returns `None` if the message is not a known numeric, or if it's missing arguments.
+    pub fn parse(msg: &Message) -> Option<Reply> {
+        let args = &msg.args;
+        let arg = |i: usize| args.get(i).cloned();
+        let text = || args.last().cloned().unwrap_or_default();
+
+        Some(match msg.code {
+            Code::RplWelcome => Reply::Welcome { nickname: arg(0)?, message: text() },
+            Code::RplYourhost => Reply::YourHost { message: text() },
+            Code::RplCreated => Reply::Created { message: text() },
+            Code::RplMyinfo => Reply::MyInfo {
+                server: arg(1)?,
+                version: arg(2)?,
+                user_modes: arg(3)?,
+                channel_modes: arg(4)?,
+            },
+            Code::RplBounce if args.len() > 2 => Reply::ISupport { tokens: middle(args) },
+            Code::RplLuserclient => Reply::LuserClient { message: text() },
+            Code::RplLuserop => Reply::LuserOp { count: num(args, 1)? },
+            Code::RplLuserunknown => Reply::LuserUnknown { count: num(args, 1)? },
+            Code::RplLuserchannels => Reply::LuserChannels { count: num(args, 1)? },
+            Code::RplLuserme => Reply::LuserMe { message: text() },
+            Code::RplAway => Reply::Away { nickname: arg(1)?, message: text() },
+            Code::RplIson => Reply::IsOn { nicknames: text().split_whitespace().map(String::from).collect() },
+            Code::RplUnaway => Reply::Unaway,
+            Code::RplNowaway => Reply::NowAway,
+            Code::RplWhoisuser => Reply::WhoisUser {
+                nickname: arg(1)?,
+                username: arg(2)?,
+                hostname: arg(3)?,
+                realname: arg(5)?,
+            },
+            Code::RplWhoisserver => Reply::WhoisServer { nickname: arg(1)?, server: arg(2)?, info: text() },
+            Code::RplWhoisoperator => Reply::WhoisOperator { nickname: arg(1)? },
+            Code::RplWhoisidle => Reply::WhoisIdle {
+                nickname: arg(1)?,
+                idle: num(args, 2)?,
+                signon: if args.len() > 4 { num(args, 3) } else { None },
+            },
+            Code::RplEndofwhois => Reply::EndOfWhois { nickname: arg(1)? },
+            Code::RplWhoischannels => Reply::WhoisChannels {
+                nickname: arg(1)?,
+                channels: args.get(2)?.split_whitespace().map(String::from).collect(),
+            },
+            Code::Unknown(ref code) if code == "330" => Reply::WhoisAccount { nickname: arg(1)?, account: arg(2)? },
+            Code::RplListstart => Reply::ListStart,
+            Code::RplList => Reply::List {
+                channel: arg(1)?,
+                users: num(args, 2)?,
+                topic: arg(3).unwrap_or_default(),
+            },
+            Code::RplListend => Reply::ListEnd,
+            Code::RplChannelmodeis => Reply::ChannelModeIs {
+                channel: arg(1)?,
+                modes: arg(2)?,
+                params: args[3..].to_vec(),
+            },
+            Code::RplNotopic => Reply::NoTopic { channel: arg(1)? },
+            Code::RplTopic => Reply::Topic { channel: arg(1)?, topic: arg(2)? },
+            Code::RplInviting => Reply::Inviting { nickname: arg(1)?, channel: arg(2)? },
+            Code::RplInvitelist => Reply::InviteList {
+                channel: arg(1)?,
+                mask: arg(2)?,
+                set_by: arg(3),
+                set_at: num(args, 4),
+            },
+            Code::RplEndofinvitelist => Reply::EndOfInviteList { channel: arg(1)? },
+            Code::RplNamreply => {
+                // Some servers leave out the visibility of the channel.
+                let (visibility, channel) = match args.len() {
+                    3 => ('=', arg(1)?),
+                    _ => (arg(1)?.chars().next()?, arg(2)?),
+                };
+                Reply::Names {
+                    visibility,
+                    channel,
+                    names: text().split_whitespace().map(String::from).collect(),
+                }
+            }
+            Code::RplEndofnames => Reply::EndOfNames { channel: arg(1)? },
+            Code::RplBanlist => Reply::BanList {
+                channel: arg(1)?,
+                mask: arg(2)?,
+                set_by: arg(3),
+                set_at: num(args, 4),
+            },
+            Code::RplEndofbanlist => Reply::EndOfBanList { channel: arg(1)? },
+            Code::RplMotdstart => Reply::MotdStart { message: text() },
+            Code::RplMotd => {
+                let line = text();
+                Reply::Motd { line: line.strip_prefix("- ").unwrap_or(&line).into() }
+            }
+            Code::RplEndofmotd => Reply::EndOfMotd,
+            Code::Unknown(ref code) if code == "730" => Reply::MonOnline { targets: split_list(&text()) },
+            Code::Unknown(ref code) if code == "731" => Reply::MonOffline { nicknames: split_list(&text()) },
+            Code::Unknown(ref code) if code == "732" => Reply::MonList { nicknames: split_list(&text()) },
+            Code::Unknown(ref code) if code == "733" => Reply::EndOfMonList,
+            Code::Unknown(ref code) if code == "734" => Reply::MonListFull {
+                limit: num(args, 1)?,
+                nicknames: split_list(&arg(2)?),
+            },
+            ref code if is_error(code) => Reply::Error { code: code.clone(), params: middle(args), message: text() },
+            _ => return None,
+        })
+    }
+
+}
+
+// loirc doesn't know every error numeric, the others are parsed as `Code::Unknown`.
+fn is_error(code: &Code) -> bool {
+    match *code {
+        Code::Unknown(ref code) => code.len() == 3 && code.parse().map(|n: u16| (400..600).contains(&n)).unwrap_or(false),
+        ref code => code.is_error(),
+    }
+}
+
+// Parse a numeric argument.
+fn num<T: FromStr>(args: &[String], i: usize) -> Option<T> {
+    args.get(i).and_then(|a| a.parse().ok())
+}
+
+// Arguments between our nickname and the trailing text.
+fn middle(args: &[String]) -> Vec<String> {
+    if args.len() > 2 {
+        args[1..args.len() - 1].to_vec()
+    } else {
+        Vec::new()
+    }
+}
+
+// Split a comma separated list.
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',').filter(|s| !s.is_empty()).map(String::from).collect()
+}
+
+#[cfg(test)]
+fn message(line: &str) -> Message {
+    Message::parse(line).unwrap()
+}
+
+#[test]
+fn test_parse_replies() {
+    assert_eq!(Reply::parse(&message(":srv 004 me srv.example 2.0 iow beklmnt")), Some(Reply::MyInfo {
+        server: "srv.example".into(),
+        version: "2.0".into(),
+        user_modes: "iow".into(),
+        channel_modes: "beklmnt".into(),
+    }));
+    assert_eq!(Reply::parse(&message(":srv 353 me @ #rust :@alice +bob carol")), Some(Reply::Names {
+        visibility: '@',
+        channel: "#rust".into(),
+        names: vec!["@alice".into(), "+bob".into(), "carol".into()],
+    }));
+    assert_eq!(Reply::parse(&message(":srv 322 me #rust 42 :[+nt] Rust")), Some(Reply::List {
+        channel: "#rust".into(),
+        users: 42,
+        topic: "[+nt] Rust".into(),
+    }));
+    assert_eq!(Reply::parse(&message(":srv 346 me #rust *!*@friend.org op 1500000000")), Some(Reply::InviteList {
+        channel: "#rust".into(),
+        mask: "*!*@friend.org".into(),
+        set_by: Some("op".into()),
+        set_at: Some(1500000000),
+    }));
+    assert_eq!(Reply::parse(&message(":srv 303 me :alice bob ")), Some(Reply::IsOn {
+        nicknames: vec!["alice".into(), "bob".into()],
+    }));
+    assert_eq!(Reply::parse(&message(":srv 730 me :alice!a@host,bob!b@host")), Some(Reply::MonOnline {
+        targets: vec!["alice!a@host".into(), "bob!b@host".into()],
+    }));
+    assert_eq!(Reply::parse(&message(":srv 734 me 100 carol,dave :Monitor list is full")), Some(Reply::MonListFull {
+        limit: 100,
+        nicknames: vec!["carol".into(), "dave".into()],
+    }));
+    assert_eq!(Reply::parse(&message(":srv 372 me :- Hello")), Some(Reply::Motd { line: "Hello".into() }));
+    assert_eq!(Reply::parse(&message(":srv 005 me CHANTYPES=# NICKLEN=9 :are supported")), Some(Reply::ISupport {
+        tokens: vec!["CHANTYPES=#".into(), "NICKLEN=9".into()],
+    }));
+}
+
+#[test]
+fn test_parse_errors() {
+    assert_eq!(Reply::parse(&message(":srv 474 me #rust :Cannot join channel (+b)")), Some(Reply::Error {
+        code: Code::ErrBannedfromchan,
+        params: vec!["#rust".into()],
+        message: "Cannot join channel (+b)".into(),
+    }));
+    assert_eq!(Reply::parse(&message(":srv 435 me bob #rust :Cannot change nickname")), Some(Reply::Error {
+        code: Code::Unknown("435".into()),
+        params: vec!["bob".into(), "#rust".into()],
+        message: "Cannot change nickname".into(),
+    }));
+    assert_eq!(Reply::parse(&message(":srv 252 me many :operators online")), None);
+    assert_eq!(Reply::parse(&message(":nick!user@host PRIVMSG #rust :hi")), None);
+}
@@ -1,8 +1,14 @@
+use std::time::Duration;
+
 use encoding::EncodingRef;
 use encoding::all::UTF_8;
 use loirc::{MonitorSettings, ReconnectionSettings};
-use ::core::{dispatch, Error};
+use ::chain::Chain;
+use ::core::{dispatch, dispatch_chain, Error};
+use ::event::Events;
+use ::lag::LagSettings;
 use ::listener::Listener;
+use ::rejoin::KickRejoin;
 
 /// Settings for the dispatcher.
 pub struct Settings<'a> {
@@ -26,6 +32,30 @@ pub struct Settings<'a> {
     pub encoding: EncodingRef,
     /// Server password
     pub password: &'a str,
+    /// Name of the network, used to tell connections apart.
+    pub network: &'a str,
+    /// Channels to join once registered, with their key.
+    pub autojoin: Vec<(&'a str, Option<&'a str>)>,
+    /// Rejoin the channels we were in after a reconnection.
+    ///
+    /// When enabled, which is the default, the channels are kept across a disconnection
+    /// instead of being cleared, and are desynced until we rejoin them, see
+    /// `Channel::is_desynced`. When disabled, the channels are cleared on disconnection.
+    pub rejoin: bool,
+    /// Rejoin channels after being kicked. If None, channels are not rejoined.
+    pub kick_rejoin: Option<KickRejoin>,
+    /// Hostmasks of the users whose invites are followed by joining the channel.
+    pub join_on_invite: Vec<&'a str>,
+    /// Interval between ISON requests, for servers without MONITOR or when their monitor list is full.
+    pub ison_interval: Duration,
+    /// Lag measurement settings. If None, the lag is not measured.
+    pub lag: Option<LagSettings>,
+    /// Time allowed to complete the registration before reconnecting. If None, there's no limit.
+    pub registration_timeout: Option<Duration>,
+    /// Capabilities requested from the server, when it offers them.
+    pub caps: Vec<&'a str>,
+    /// Account and password used to authenticate with SASL PLAIN. If None, SASL is not used.
+    pub sasl: Option<(&'a str, &'a str)>,
 }
 
 impl<'a> Settings<'a> {
@@ -42,6 +72,16 @@ impl<'a> Settings<'a> {
     /// auto_ident: true,
     /// auto_ping: true,
     /// encoding: UTF_8,
+    /// network: addr,
+    /// autojoin: [],
+    /// rejoin: true,
+    /// kick_rejoin: None,
+    /// join_on_invite: [],
+    /// ison_interval: 60 seconds,
+    /// lag: None,
+    /// registration_timeout: None,
+    /// caps: [],
+    /// sasl: None,
     /// ```
     pub fn new<'b>(addr: &'b str, nickname: &'b str) -> Settings<'b> {
         Settings {
@@ -55,6 +95,16 @@ impl<'a> Settings<'a> {
             auto_ping: true,
             encoding: UTF_8,
             password: "",
+            network: addr,
+            autojoin: Vec::new(),
+            rejoin: true,
+            kick_rejoin: None,
+            join_on_invite: Vec::new(),
+            ison_interval: Duration::from_secs(60),
+            lag: None,
+            registration_timeout: None,
+            caps: Vec::new(),
+            sasl: None,
         }
     }
 
@@ -106,6 +156,68 @@ impl<'a> Settings<'a> {
         self
     }
 
+    /// Modify the name of the network.
+    pub fn network(mut self, network: &'a str) -> Settings<'a> {
+        self.network = network;
+        self
+    }
+
+    /// Add a channel to join once registered, with its key if it has one.
+    pub fn autojoin(mut self, channel: &'a str, key: Option<&'a str>) -> Settings<'a> {
+        self.autojoin.push((channel, key));
+        self
+    }
+
+    /// Enable/disable rejoining channels after a reconnection.
+    pub fn rejoin(mut self, rejoin: bool) -> Settings<'a> {
+        self.rejoin = rejoin;
+        self
+    }
+
+    /// Modify the settings of the rejoin after a kick.
+    pub fn kick_rejoin(mut self, kick_rejoin: Option<KickRejoin>) -> Settings<'a> {
+        self.kick_rejoin = kick_rejoin;
+        self
+    }
+
+    /// Join the channels we are invited to by users matching this hostmask.
+    ///
+    /// Use `*!*@*` to follow every invite.
+    pub fn join_on_invite(mut self, mask: &'a str) -> Settings<'a> {
+        self.join_on_invite.push(mask);
+        self
+    }
+
+    /// Modify the interval between ISON requests.
+    pub fn ison_interval(mut self, ison_interval: Duration) -> Settings<'a> {
+        self.ison_interval = ison_interval;
+        self
+    }
+
+    /// Modify the lag measurement settings.
+    pub fn lag(mut self, lag: Option<LagSettings>) -> Settings<'a> {
+        self.lag = lag;
+        self
+    }
+
+    /// Modify the time allowed to complete the registration.
+    pub fn registration_timeout(mut self, registration_timeout: Option<Duration>) -> Settings<'a> {
+        self.registration_timeout = registration_timeout;
+        self
+    }
+
+    /// Request a capability from the server, if it offers it.
+    pub fn cap(mut self, cap: &'a str) -> Settings<'a> {
+        self.caps.push(cap);
+        self
+    }
+
+    /// Authenticate with SASL PLAIN, using an account and its password.
+    pub fn sasl(mut self, account: &'a str, password: &'a str) -> Settings<'a> {
+        self.sasl = Some((account, password));
+        self
+    }
+
     /// Connect to the server and begin dispatching events using the given `Listener`.
     pub fn dispatch<L>(self, listener: L) -> Result<(), Error>
         where L: Listener
@@ -113,4 +225,14 @@ impl<'a> Settings<'a> {
         dispatch(listener, self)
     }
 
+    /// Connect to the server and begin dispatching events through the given `Chain`.
+    pub fn dispatch_chain(self, chain: Chain) -> Result<(), Error> {
+        dispatch_chain(chain, self)
+    }
+
+    /// Connect to the server and iterate over the events, instead of using a `Listener`.
+    pub fn events(self) -> Result<Events<'a>, Error> {
+        Events::new(self)
+    }
+
 }
@@ -0,0 +1,88 @@
+// Helpers for the tests which need a connection, with a local server standing in for the
+// irc server.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use chain::Chain;
+use core::Dispatch;
+use loirc::{self, Message};
+use settings::Settings;
+
+pub struct TestServer {
+    listener: TcpListener,
+    addr: String,
+}
+
+impl TestServer {
+
+    pub fn new() -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        TestServer { listener, addr }
+    }
+
+    #[cfg(feature = "async")]
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn settings(&self) -> Settings<'_> {
+        Settings::new(&self.addr, "me")
+    }
+
+    /// Accept the connection of a client.
+    pub fn accept(&self) -> TestClient {
+        let (stream, _) = self.listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        TestClient { stream: BufReader::new(stream) }
+    }
+
+    /// Connect a dispatcher, returns it with the server side of the connection.
+    pub fn connect<'a>(&self, chain: Chain<'a>, settings: Settings<'a>) -> (Dispatch<'a>, TestClient) {
+        let (dispatch, _) = Dispatch::connect(chain, settings).unwrap();
+        (dispatch, self.accept())
+    }
+
+    pub fn dispatch(&self) -> (Dispatch<'_>, TestClient) {
+        self.connect(Chain::new(), self.settings())
+    }
+
+}
+
+/// Server side of a connection, to read what the client sent.
+pub struct TestClient {
+    stream: BufReader<TcpStream>,
+}
+
+impl TestClient {
+
+    /// Read the next line sent by the client.
+    pub fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.stream.read_line(&mut line).unwrap();
+        line.trim_end().into()
+    }
+
+    /// Send a line to the client.
+    pub fn send(&mut self, line: &str) {
+        write!(self.stream.get_mut(), "{}\r\n", line).unwrap();
+    }
+
+    /// Read lines until one starts with the given text, returns it.
+    pub fn until(&mut self, start: &str) -> String {
+        loop {
+            let line = self.line();
+            assert!(!line.is_empty(), "no line starting with {:?}", start);
+            if line.starts_with(start) {
+                return line;
+            }
+        }
+    }
+
+}
+
+pub fn message(line: &str) -> loirc::Event {
+    loirc::Event::Message(Message::parse(line).unwrap())
+}